@@ -0,0 +1,62 @@
+use clap::{Parser, Subcommand};
+
+#[derive(Parser)]
+#[command(name = "resk", version, about = "Shared clipboard for your devices")]
+pub struct Cli {
+    /// Directory with keys, database and config [default: ~/.resk]
+    #[arg(long, global = true)]
+    pub data_dir: Option<String>,
+
+    /// Print raw JSON instead of text
+    #[arg(long, global = true)]
+    pub json: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the node, the default without a command
+    Daemon,
+    /// Discover nodes on the local network
+    Scan,
+    /// Pair with a discovered node by node ID or IP:port
+    Pair { target: String },
+    /// Manage paired peers
+    Peers {
+        #[command(subcommand)]
+        command: PeersCommand,
+    },
+    /// Share text with all peers, read from stdin if omitted
+    Send { content: Option<String> },
+    /// Show the state of the running node
+    Status,
+    /// Inspect the node keys
+    Keys {
+        #[command(subcommand)]
+        command: KeysCommand,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum PeersCommand {
+    /// List paired peers
+    List,
+    /// Unpair a peer
+    Remove {
+        peer_id: String,
+        /// Also block the peer from pairing again
+        #[arg(long)]
+        revoke: bool,
+        /// Tell the peer it was removed
+        #[arg(long)]
+        notify: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum KeysCommand {
+    /// Show the public keys of the node
+    Show,
+}
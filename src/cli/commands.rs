@@ -0,0 +1,379 @@
+use reqwest::{Client, Method};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::{self, Read, Write};
+use std::net::Ipv4Addr;
+use tokio::time::Duration;
+
+use super::args::{Cli, Command, KeysCommand, PeersCommand};
+use crate::utils::config::{get_config, load_config};
+use crate::utils::controllers::run;
+use crate::utils::encryption::{get_fingerprint, load_api_token};
+use crate::utils::error::Error;
+use crate::utils::general::set_data_dir;
+
+// Scan alone waits a few seconds for hosts to answer
+const REQUEST_TIMEOUT: u64 = 30;
+
+#[derive(Deserialize)]
+struct ControlResponse {
+    success: bool,
+    data: Option<Value>,
+    msg: Option<String>,
+}
+
+// Talks to the control API of the daemon running on this host
+struct ControlClient {
+    client: Client,
+    base_url: String,
+    api_token: String,
+}
+
+impl ControlClient {
+    fn new() -> Result<Self, Error> {
+        load_config()?;
+        let api_token = load_api_token().map_err(|_| {
+            Error::Generic("No API token, has the daemon been started?".into())
+        })?;
+        Ok(Self {
+            client: Client::builder()
+                .timeout(Duration::from_secs(REQUEST_TIMEOUT))
+                .build()?,
+            base_url: format!(
+                "http://{}:{}",
+                Ipv4Addr::LOCALHOST,
+                get_config().control_port
+            ),
+            api_token,
+        })
+    }
+
+    async fn request(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> Result<Value, Error> {
+        let mut request = self
+            .client
+            .request(method, format!("{}{}", self.base_url, path))
+            .bearer_auth(&self.api_token);
+        if let Some(body) = body {
+            request = request
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(serde_json::to_string(&body)?);
+        }
+
+        let response = request.send().await.map_err(|err| {
+            if err.is_connect() {
+                Error::Generic("Daemon is not running".into())
+            } else {
+                err.into()
+            }
+        })?;
+        let response: ControlResponse =
+            serde_json::from_str(&response.text().await?)?;
+        if !response.success {
+            return Err(Error::Daemon(response.msg.unwrap_or_default()));
+        }
+        Ok(response.data.unwrap_or(Value::Null))
+    }
+
+    async fn get(&self, path: &str) -> Result<Value, Error> {
+        self.request(Method::GET, path, None).await
+    }
+
+    async fn post(&self, path: &str, body: Value) -> Result<Value, Error> {
+        self.request(Method::POST, path, Some(body)).await
+    }
+}
+
+pub async fn execute(cli: Cli) -> Result<(), Error> {
+    if let Some(data_dir) = cli.data_dir {
+        set_data_dir(data_dir);
+    }
+
+    let json = cli.json;
+    let command = match cli.command {
+        None | Some(Command::Daemon) => return run().await,
+        Some(command) => command,
+    };
+    let client = ControlClient::new()?;
+    match command {
+        Command::Daemon => unreachable!(),
+        Command::Scan => scan(&client, json).await,
+        Command::Pair { target } => pair(&client, &target, json).await,
+        Command::Peers {
+            command: PeersCommand::List,
+        } => list_peers(&client, json).await,
+        Command::Peers {
+            command:
+                PeersCommand::Remove {
+                    peer_id,
+                    revoke,
+                    notify,
+                },
+        } => remove_peer(&client, &peer_id, revoke, notify, json).await,
+        Command::Send { content } => send(&client, content, json).await,
+        Command::Status => status(&client, json).await,
+        Command::Keys {
+            command: KeysCommand::Show,
+        } => show_keys(&client, json).await,
+    }
+}
+
+async fn scan(client: &ControlClient, json: bool) -> Result<(), Error> {
+    let data = client.get("/scan").await?;
+    if json {
+        return print_json(&data);
+    }
+
+    let hosts = list(&data["ip_list"]);
+    if hosts.is_empty() {
+        println!("No hosts found");
+        return Ok(());
+    }
+    println!("{:<24} {:<16} {:<6} NODE ID", "HOSTNAME", "IP", "PORT");
+    for host in &hosts {
+        let node_id = get_fingerprint(&field(host, "verify_key").to_owned())?;
+        println!(
+            "{:<24} {:<16} {:<6} {}",
+            field(host, "hostname"),
+            field(host, "ip"),
+            host["port"].to_string(),
+            node_id
+        );
+    }
+    Ok(())
+}
+
+async fn pair(
+    client: &ControlClient,
+    target: &str,
+    json: bool,
+) -> Result<(), Error> {
+    // The other side asked first, answer its request
+    let requests = client.get("/pairing_requests").await?;
+    let incoming = list(&requests["incoming"]);
+    if let Some(request) = find_target(&incoming, "pub_key", target)? {
+        let sas = field(&request, "sas");
+        let data = if confirm_sas(field(&request, "hostname"), sas)? {
+            let path = format!("/pairing_requests/{}/accept", request["id"]);
+            client.post(&path, json!({ "sas": sas })).await?
+        } else {
+            let path = format!("/pairing_requests/{}/reject", request["id"]);
+            client.post(&path, json!({})).await?
+        };
+        return print_pairing(&data, field(&request, "hostname"), json);
+    }
+
+    let hosts = client.get("/scan").await?;
+    let hosts = list(&hosts["ip_list"]);
+    let target =
+        find_target(&hosts, "verify_key", target)?.ok_or(Error::Generic(
+            format!("Node {} not found on the network", target).into(),
+        ))?;
+    let data = client
+        .post(
+            "/connect_peer",
+            json!({
+                "pub_key": target["verify_key"],
+                "hostname": target["hostname"],
+                "ip": target["ip"],
+                "port": target["port"],
+                "agreement_key": target["agreement_key"],
+            }),
+        )
+        .await?;
+
+    // Our request is confirmed once the user compared the codes
+    let requests = client.get("/pairing_requests").await?;
+    let request = list(&requests["outgoing"])
+        .iter()
+        .find(|request| request["pub_key"] == target["verify_key"])
+        .cloned()
+        .ok_or(Error::Generic("Pairing request disappeared".into()))?;
+    let sas = field(&data, "sas");
+    let data = if confirm_sas(field(&target, "hostname"), sas)? {
+        let path = format!("/pairing_requests/{}/confirm", request["id"]);
+        client.post(&path, json!({ "sas": sas })).await?
+    } else {
+        let path = format!("/pairing_requests/{}/reject", request["id"]);
+        client.post(&path, json!({})).await?
+    };
+    print_pairing(&data, field(&target, "hostname"), json)
+}
+
+// Both users have to see the same code, anything else aborts
+fn confirm_sas(hostname: &str, sas: &str) -> Result<bool, Error> {
+    eprintln!("Pairing code: {}", sas);
+    eprint!("Does {} show the same code? [y/N] ", hostname);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+fn print_pairing(
+    data: &Value,
+    hostname: &str,
+    json: bool,
+) -> Result<(), Error> {
+    if json {
+        return print_json(data);
+    }
+
+    match field(data, "status") {
+        "paired" => println!("Paired with {}", hostname),
+        "pending" => println!("Waiting for {} to confirm", hostname),
+        "rejected" => println!("Pairing with {} rejected", hostname),
+        status => println!("Pairing with {}: {}", hostname, status),
+    }
+    Ok(())
+}
+
+async fn list_peers(client: &ControlClient, json: bool) -> Result<(), Error> {
+    let data = client.get("/peers").await?;
+    if json {
+        return print_json(&data);
+    }
+
+    let peers = list(&data["peers"]);
+    if peers.is_empty() {
+        println!("No paired peers");
+        return Ok(());
+    }
+    println!("{:<24} {:<16} {:<8} PEER ID", "NAME", "IP", "SHARING");
+    for peer in &peers {
+        let name = peer["nickname"].as_str().unwrap_or(field(peer, "hostname"));
+        let sharing = if peer["enabled"] == json!(true) {
+            "on"
+        } else {
+            "paused"
+        };
+        println!(
+            "{:<24} {:<16} {:<8} {}",
+            name,
+            field(peer, "ip"),
+            sharing,
+            field(peer, "peer_id")
+        );
+    }
+    Ok(())
+}
+
+async fn remove_peer(
+    client: &ControlClient,
+    peer_id: &str,
+    revoke: bool,
+    notify: bool,
+    json: bool,
+) -> Result<(), Error> {
+    let action = if revoke { "revoke" } else { "unpair" };
+    let path = format!("/peers/{}/{}", peer_id, action);
+    let data = client.post(&path, json!({ "notify": notify })).await?;
+    if json {
+        return print_json(&data);
+    }
+
+    println!("Peer {} {}", peer_id, field(&data, "status"));
+    if notify && data["notified"] != json!(true) {
+        println!("The peer could not be notified");
+    }
+    Ok(())
+}
+
+async fn send(
+    client: &ControlClient,
+    content: Option<String>,
+    json: bool,
+) -> Result<(), Error> {
+    let content = match content {
+        Some(content) => content,
+        None => {
+            let mut content = String::new();
+            io::stdin().read_to_string(&mut content)?;
+            content
+        }
+    };
+    let data = client.post("/send", json!({ "content": content })).await?;
+    if json {
+        return print_json(&data);
+    }
+
+    println!("Shared {} bytes with peers", content.len());
+    Ok(())
+}
+
+async fn status(client: &ControlClient, json: bool) -> Result<(), Error> {
+    let data = client.get("/status").await?;
+    if json {
+        return print_json(&data);
+    }
+
+    println!("Version:           {}", field(&data, "version"));
+    println!("Node ID:           {}", field(&data, "node_id"));
+    println!("Hostname:          {}", field(&data, "hostname"));
+    println!("Data directory:    {}", field(&data, "data_dir"));
+    println!("Node port:         {}", data["node_port"]);
+    println!("Control port:      {}", data["control_port"]);
+    println!("Peers:             {}", data["peers"]);
+    println!("Pairing requests:  {}", data["pairing_requests"]);
+    Ok(())
+}
+
+async fn show_keys(client: &ControlClient, json: bool) -> Result<(), Error> {
+    let data = client.get("/keys").await?;
+    if json {
+        return print_json(&data);
+    }
+
+    println!("Node ID:        {}", field(&data, "node_id"));
+    println!("Verify key:     {}", field(&data, "verify_key"));
+    println!("Agreement key:  {}", field(&data, "agreement_key"));
+    Ok(())
+}
+
+fn print_json(data: &Value) -> Result<(), Error> {
+    println!("{}", serde_json::to_string_pretty(data)?);
+    Ok(())
+}
+
+// Hostnames and bare IPs repeat across nodes, so only the node ID or the
+// IP with its port picks one
+fn find_target(
+    values: &[Value],
+    key_field: &str,
+    target: &str,
+) -> Result<Option<Value>, Error> {
+    let matches: Vec<&Value> = values
+        .iter()
+        .filter(|value| {
+            let node_id =
+                get_fingerprint(&field(value, key_field).to_owned()).ok();
+            let address = format!("{}:{}", field(value, "ip"), value["port"]);
+            node_id.as_deref() == Some(target) || address == target
+        })
+        .collect();
+    let first = match matches.first() {
+        Some(first) => *first,
+        None => return Ok(None),
+    };
+    if matches
+        .iter()
+        .any(|value| value[key_field] != first[key_field])
+    {
+        return Err(Error::Generic(
+            format!("{} matches several nodes, use the node ID", target).into(),
+        ));
+    }
+    Ok(Some(first.clone()))
+}
+
+fn field<'a>(value: &'a Value, key: &str) -> &'a str {
+    value[key].as_str().unwrap_or("-")
+}
+
+fn list(value: &Value) -> Vec<Value> {
+    value.as_array().cloned().unwrap_or_default()
+}
@@ -1,15 +1,29 @@
 use actix_web::web;
-use reqwest::Client;
 use tokio::sync::Mutex;
 use tokio::time::{sleep, Duration};
 
-use crate::utils::db::Database;
-use crate::utils::encryption::get_digest;
+use crate::connect::routes::{
+    ConfirmPairingArgs, ConnectPeerArgs, EchoArgs, HandoverArgs, PairArgs,
+    PeerListResponse, PeerResponse, RemovePeerArgs, UnpairedArgs,
+    UpdatePeerArgs,
+};
+use crate::entity::{pairing_request, peer};
+use crate::share::controllers::NonceCache;
+use crate::utils::channel::PeerChannels;
+use crate::utils::communication::{
+    reach_peer, send_multicast_msg, Beacon, BEACON_PROBE,
+};
+use crate::utils::config::get_config;
+use crate::utils::db::{Database, NewPairingRequest, PeerChanges};
+use crate::utils::encryption::{
+    generate_nonce, get_fingerprint, get_node_id, get_sas, get_verify_key,
+    sign_message, verify_message, NodeKeys,
+};
 use crate::utils::error::Error;
-use crate::utils::general::get_verify_key_path;
-use crate::{
-    connect::routes::ConnectPeerArgs, utils::communication::send_multicast_msg,
+use crate::utils::general::{
+    get_agreement_pub_key_path, get_data_dir, get_timestamp, is_fresh,
 };
+use crate::utils::tls::{get_response_pub_key, PeerClient, PeerIdentity};
 use base64::engine::general_purpose::URL_SAFE_NO_PAD;
 use base64::Engine;
 use hostname::get as get_hostname;
@@ -19,6 +33,7 @@ use std::sync::Arc;
 
 pub mod echo_helpers {
     use local_ip_address::linux::local_ip;
+    use serde_json::Value;
 
     #[cfg(target_os = "linux")]
     pub async fn get_local_ip() -> String {
@@ -35,66 +50,614 @@ pub mod echo_helpers {
     pub async fn get_local_ip() -> String {
         todo!()
     }
+
+    // Content covered by the signature in an echo response
+    pub fn signed_content(challenge: &str, data: &Value) -> String {
+        let field = |name: &str| data[name].as_str().unwrap_or("").to_owned();
+        format!(
+            "{}.{}.{}.{}.{}.{}",
+            challenge,
+            field("verify_key"),
+            field("agreement_key"),
+            field("hostname"),
+            field("ip"),
+            data["port"]
+        )
+    }
 }
 
-pub async fn echo() -> Result<Value, Error> {
-    let verify_key_bytes = fs::read(get_verify_key_path().as_str())?;
-    let verify_key_encoded_str = URL_SAFE_NO_PAD.encode(verify_key_bytes);
+pub async fn echo(args: &EchoArgs) -> Result<Value, Error> {
+    let verify_key_encoded_str = get_verify_key()?;
+    let agreement_key_bytes = fs::read(get_agreement_pub_key_path().as_str())?;
+    let agreement_key_encoded_str = URL_SAFE_NO_PAD.encode(agreement_key_bytes);
     let hostname = get_hostname()?.to_string_lossy().to_string();
     let local_ip = echo_helpers::get_local_ip().await;
 
-    Ok(
-        json!({"verify_key": verify_key_encoded_str, "hostname": hostname, "ip": local_ip}),
-    )
+    // Prove the key is ours, not copied from another node's echo
+    let mut data = json!({
+        "verify_key": verify_key_encoded_str,
+        "agreement_key": agreement_key_encoded_str,
+        "hostname": hostname,
+        "ip": local_ip,
+        "port": get_config().node_port
+    });
+    let signature =
+        sign_message(&echo_helpers::signed_content(&args.challenge, &data))
+            .await?;
+    data["signature"] = json!(signature);
+
+    Ok(data)
+}
+
+pub const INCOMING: &str = "incoming";
+pub const OUTGOING: &str = "outgoing";
+// Keys that aren't paired can't queue more requests than this
+const MAX_INCOMING_PAIRING_REQUESTS: u64 = 16;
+
+mod pair_helpers {
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+    use hostname::get as get_hostname;
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+    use std::fs;
+
+    use super::echo_helpers::get_local_ip;
+    use crate::connect::routes::PairArgs;
+    use crate::entity::pairing_request;
+    use crate::utils::config::get_config;
+    use crate::utils::db::Database;
+    use crate::utils::encryption::{
+        generate_nonce, get_content_hash, get_sas, get_verify_key, sign_message,
+    };
+    use crate::utils::error::Error;
+    use crate::utils::general::{get_agreement_pub_key_path, get_timestamp};
+    use crate::utils::tls::PeerClient;
+    use reqwest::Client;
+
+    #[derive(Debug, Deserialize)]
+    struct PairResponse {
+        success: bool,
+        data: Option<Value>,
+        msg: Option<String>,
+    }
+
+    // Describe this node, signed with its own key
+    pub async fn build_pair_args(
+        sas_commitment: Option<String>,
+        sas_nonce: Option<String>,
+    ) -> Result<PairArgs, Error> {
+        let agreement_key_bytes =
+            fs::read(get_agreement_pub_key_path().as_str())?;
+        let mut args = PairArgs {
+            pub_key: get_verify_key()?,
+            agreement_key: URL_SAFE_NO_PAD.encode(agreement_key_bytes),
+            hostname: get_hostname()?.to_string_lossy().to_string(),
+            ip: get_local_ip().await,
+            port: get_config().node_port,
+            timestamp: get_timestamp(),
+            nonce: generate_nonce()?,
+            sas_commitment,
+            sas_nonce,
+            signature: String::new(),
+        };
+        args.signature = sign_message(&args.signed_content()).await?;
+        Ok(args)
+    }
+
+    pub async fn send_pair_message<T: Serialize>(
+        client: &PeerClient,
+        ip: &String,
+        port: u16,
+        pub_key: &str,
+        path: &str,
+        args: &T,
+    ) -> Result<Value, Error> {
+        post_pair_message(&client.get(pub_key)?, ip, port, path, args).await
+    }
+
+    pub async fn post_pair_message<T: Serialize>(
+        client: &Client,
+        ip: &String,
+        port: u16,
+        path: &str,
+        args: &T,
+    ) -> Result<Value, Error> {
+        let url = format!("https://{}:{}/{}", ip, port, path);
+        let response = client
+            .post(&url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_string(args)?)
+            .send()
+            .await?;
+        let response = response.text().await?;
+        let response: PairResponse = serde_json::from_str(&response)?;
+        if !response.success {
+            return Err(Error::Generic(
+                format!(
+                    "Peer {} refused pairing message: {}",
+                    ip,
+                    response.msg.unwrap_or_default()
+                )
+                .into(),
+            ));
+        }
+        Ok(response.data.unwrap_or_default())
+    }
+
+    // Commitment the requester sends before its nonce is revealed
+    pub fn commit_sas_nonce(sas_nonce: &str) -> String {
+        get_content_hash(sas_nonce)
+    }
+
+    pub fn matches_sas_commitment(
+        commitment: Option<&String>,
+        sas_nonce: &str,
+    ) -> bool {
+        commitment == Some(&commit_sas_nonce(sas_nonce))
+    }
+
+    // Code for the request, once both nonces are known
+    pub fn request_sas(
+        request: &pairing_request::Model,
+        verify_key: &String,
+    ) -> Result<String, Error> {
+        let peer_sas_nonce = request
+            .peer_sas_nonce
+            .as_ref()
+            .ok_or(Error::Generic("Pairing code isn't ready yet".into()))?;
+        get_sas(
+            verify_key,
+            &request.pub_key,
+            &request.sas_nonce,
+            peer_sas_nonce,
+        )
+    }
+
+    pub fn describe_request(
+        request: &pairing_request::Model,
+        verify_key: &String,
+    ) -> Result<Value, Error> {
+        Ok(json!({
+            "id": request.id,
+            "pub_key": request.pub_key,
+            "hostname": request.hostname,
+            "ip": request.ip,
+            "port": request.port,
+            "sas": request_sas(request, verify_key)?,
+            "accepted": request.accepted,
+            "confirmed": request.confirmed,
+            "created_at": request.created_at,
+        }))
+    }
+
+    pub fn check_sas(
+        request: &pairing_request::Model,
+        sas: &str,
+    ) -> Result<(), Error> {
+        let expected = request_sas(request, &get_verify_key()?)?;
+        let normalize = |code: &str| code.replace(char::is_whitespace, "");
+        if normalize(&expected) != normalize(sas) {
+            return Err(Error::Generic("Pairing codes don't match".into()));
+        }
+        Ok(())
+    }
+
+    pub async fn commit_request(
+        db: &Database,
+        request: &pairing_request::Model,
+    ) -> Result<(), Error> {
+        db.insert_peer(
+            &request.pub_key,
+            &request.hostname,
+            &request.ip,
+            request.port as u16,
+            &request.agreement_key,
+        )
+        .await?;
+        db.delete_pairing_request(request.id).await?;
+        log::info!("Paired with {} ({})", &request.hostname, &request.ip);
+        Ok(())
+    }
 }
 
-pub async fn connect_peer(args: &ConnectPeerArgs) -> Result<Value, Error> {
+pub async fn connect_peer(
+    args: &ConnectPeerArgs,
+    db: &Database,
+    client: &PeerClient,
+) -> Result<Value, Error> {
     // Define args
     let pub_key = &args.pub_key;
     let hostname = &args.hostname;
     let ip = &args.ip;
+    let agreement_key = &args.agreement_key;
+    let port = args.port;
+
+    // Remember the request, so the answer can be matched against it
+    let sas_nonce = generate_nonce()?;
+    db.insert_pairing_request(
+        OUTGOING,
+        NewPairingRequest {
+            pub_key,
+            agreement_key,
+            hostname,
+            ip,
+            port,
+            sas_nonce: sas_nonce.clone(),
+            sas_commitment: None,
+        },
+    )
+    .await?;
 
-    // Insert data to db
-    let mut db = Database::new().await?;
-    db.insert_peer(pub_key, hostname, ip).await?;
+    let result = request_pairing(&sas_nonce, pub_key, ip, port, client).await;
+    let peer_sas_nonce = match result {
+        Ok(peer_sas_nonce) => peer_sas_nonce,
+        Err(err) => {
+            if let Some(request) =
+                db.get_pairing_request_by_key(OUTGOING, pub_key).await?
+            {
+                db.delete_pairing_request(request.id).await?;
+            }
+            return Err(err);
+        }
+    };
+    if let Some(request) =
+        db.get_pairing_request_by_key(OUTGOING, pub_key).await?
+    {
+        db.reveal_pairing_request(request, &peer_sas_nonce).await?;
+    }
+    log::info!("Pairing request sent to {}", ip);
 
-    Ok(json!({"abuben": "connected"}))
+    // Both users have to see the same code before the peer is stored
+    let sas =
+        get_sas(&get_verify_key()?, pub_key, &sas_nonce, &peer_sas_nonce)?;
+    Ok(json!({"status": "pending", "sas": sas}))
+}
+
+// Ask the other side to pair with us, returns its code nonce
+async fn request_pairing(
+    sas_nonce: &str,
+    pub_key: &str,
+    ip: &String,
+    port: u16,
+    client: &PeerClient,
+) -> Result<String, Error> {
+    // Our nonce stays hidden until the other side picked its own
+    let commitment = pair_helpers::commit_sas_nonce(sas_nonce);
+    let pair_args =
+        pair_helpers::build_pair_args(Some(commitment), None).await?;
+    let data = pair_helpers::send_pair_message(
+        client, ip, port, pub_key, "pair", &pair_args,
+    )
+    .await?;
+    let peer_sas_nonce = data["sas_nonce"]
+        .as_str()
+        .ok_or(Error::Generic("Peer sent no pairing nonce".into()))?
+        .to_owned();
+
+    let pair_args =
+        pair_helpers::build_pair_args(None, Some(sas_nonce.to_owned())).await?;
+    pair_helpers::send_pair_message(
+        client,
+        ip,
+        port,
+        pub_key,
+        "pair/reveal",
+        &pair_args,
+    )
+    .await?;
+    Ok(peer_sas_nonce)
+}
+
+pub async fn verify_pair_args(
+    args: &PairArgs,
+    peer_id: Option<String>,
+    identity: Option<&PeerIdentity>,
+) -> bool {
+    // The TLS client key has to be the one being paired
+    identity.map(|identity| &identity.0) == Some(&args.pub_key)
+        && peer_id.is_some()
+        && peer_id == get_fingerprint(&args.pub_key).ok()
+        && is_fresh(args.timestamp)
+        && verify_message(
+            &args.pub_key,
+            &args.signature,
+            &args.signed_content(),
+        )
+        .await
+        .is_ok()
+}
+
+pub async fn has_room_for_pairing_request(
+    args: &PairArgs,
+    db: &Database,
+) -> Result<bool, Error> {
+    // A repeated request only replaces the pending one
+    if db
+        .get_pairing_request_by_key(INCOMING, &args.pub_key)
+        .await?
+        .is_some()
+    {
+        return Ok(true);
+    }
+    Ok(db.count_pairing_requests(INCOMING).await?
+        < MAX_INCOMING_PAIRING_REQUESTS)
+}
+
+pub async fn pair(
+    args: &PairArgs,
+    remote_ip: &String,
+    db: &Database,
+) -> Result<Value, Error> {
+    if db.is_blocked(&get_fingerprint(&args.pub_key)?).await? {
+        return Err(Error::Generic("Peer is blocked".into()));
+    }
+    let sas_commitment = args
+        .sas_commitment
+        .clone()
+        .ok_or(Error::Generic("Pairing request has no commitment".into()))?;
+    let sas_nonce = generate_nonce()?;
+    db.insert_pairing_request(
+        INCOMING,
+        NewPairingRequest {
+            pub_key: &args.pub_key,
+            agreement_key: &args.agreement_key,
+            hostname: &args.hostname,
+            ip: remote_ip,
+            port: args.port,
+            sas_nonce: sas_nonce.clone(),
+            sas_commitment: Some(sas_commitment),
+        },
+    )
+    .await?;
+    log::info!(
+        "Got pairing request from {} ({})",
+        &args.hostname,
+        remote_ip
+    );
+
+    Ok(json!({"status": "pending", "sas_nonce": sas_nonce}))
+}
+
+pub async fn pair_reveal(
+    args: &PairArgs,
+    db: &Database,
+) -> Result<Value, Error> {
+    let request = db
+        .get_pairing_request_by_key(INCOMING, &args.pub_key)
+        .await?
+        .ok_or(Error::Generic("No pending pairing request".into()))?;
+    let sas_nonce = args
+        .sas_nonce
+        .as_ref()
+        .ok_or(Error::Generic("Pairing nonce is missing".into()))?;
+
+    // The nonce is fixed once revealed, and has to match the commitment
+    if request.peer_sas_nonce.is_some()
+        || !pair_helpers::matches_sas_commitment(
+            request.sas_commitment.as_ref(),
+            sas_nonce,
+        )
+    {
+        db.delete_pairing_request(request.id).await?;
+        return Err(Error::Generic(
+            "Pairing nonce doesn't match the commitment".into(),
+        ));
+    }
+    db.reveal_pairing_request(request, sas_nonce).await?;
+
+    Ok(json!({"status": "pending"}))
+}
+
+pub async fn pair_accept(
+    args: &PairArgs,
+    db: &Database,
+) -> Result<Value, Error> {
+    if db.is_blocked(&get_fingerprint(&args.pub_key)?).await? {
+        return Err(Error::Generic("Peer is blocked".into()));
+    }
+    let request = db
+        .get_pairing_request_by_key(OUTGOING, &args.pub_key)
+        .await?
+        .ok_or(Error::Generic("No pending pairing request".into()))?;
+
+    // Our user may not have compared the codes yet
+    if !request.confirmed {
+        db.update_pairing_request(request, true, false).await?;
+        log::info!(
+            "Pairing accepted by {}, awaiting confirmation",
+            &args.hostname
+        );
+        return Ok(json!({"status": "pending"}));
+    }
+
+    pair_helpers::commit_request(db, &request).await?;
+    Ok(json!({"status": "paired"}))
+}
+
+pub async fn pair_reject(
+    args: &PairArgs,
+    db: &Database,
+) -> Result<Value, Error> {
+    // Our own request was refused, or theirs was withdrawn
+    let mut rejected = false;
+    for direction in [OUTGOING, INCOMING] {
+        if let Some(request) = db
+            .get_pairing_request_by_key(direction, &args.pub_key)
+            .await?
+        {
+            db.delete_pairing_request(request.id).await?;
+            log::info!(
+                "Pairing rejected by {} ({})",
+                &args.hostname,
+                &request.ip
+            );
+            rejected = true;
+        }
+    }
+    if !rejected {
+        return Err(Error::Generic("No pending pairing request".into()));
+    }
+
+    Ok(json!({"status": "rejected"}))
+}
+
+pub async fn pairing_requests(db: &Database) -> Result<Value, Error> {
+    let verify_key = get_verify_key()?;
+    let mut incoming = Vec::new();
+    for request in db.get_pairing_requests(INCOMING).await? {
+        // No code to compare until the requester revealed its nonce
+        if request.peer_sas_nonce.is_none() {
+            continue;
+        }
+        incoming.push(pair_helpers::describe_request(&request, &verify_key)?);
+    }
+    let mut outgoing = Vec::new();
+    for request in db.get_pairing_requests(OUTGOING).await? {
+        if request.peer_sas_nonce.is_none() {
+            continue;
+        }
+        outgoing.push(pair_helpers::describe_request(&request, &verify_key)?);
+    }
+
+    Ok(json!({"incoming": incoming, "outgoing": outgoing}))
+}
+
+async fn get_pairing_request(
+    db: &Database,
+    id: i64,
+    direction: &str,
+) -> Result<pairing_request::Model, Error> {
+    db.get_pairing_request(id)
+        .await?
+        .filter(|request| request.direction == direction)
+        .ok_or(Error::Generic("No such pairing request".into()))
+}
+
+pub async fn accept_pairing_request(
+    id: i64,
+    args: &ConfirmPairingArgs,
+    db: &Database,
+    client: &PeerClient,
+) -> Result<Value, Error> {
+    let request = get_pairing_request(db, id, INCOMING).await?;
+    pair_helpers::check_sas(&request, &args.sas)?;
+
+    // Let the requester add us first, so both sides end up paired
+    let pair_args = pair_helpers::build_pair_args(None, None).await?;
+    pair_helpers::send_pair_message(
+        client,
+        &request.ip,
+        request.port as u16,
+        &request.pub_key,
+        "pair/accept",
+        &pair_args,
+    )
+    .await?;
+
+    pair_helpers::commit_request(db, &request).await?;
+    Ok(json!({"status": "paired"}))
+}
+
+pub async fn confirm_pairing_request(
+    id: i64,
+    args: &ConfirmPairingArgs,
+    db: &Database,
+) -> Result<Value, Error> {
+    let request = get_pairing_request(db, id, OUTGOING).await?;
+    pair_helpers::check_sas(&request, &args.sas)?;
+
+    // The other side hasn't accepted yet
+    if !request.accepted {
+        db.update_pairing_request(request, false, true).await?;
+        return Ok(json!({"status": "pending"}));
+    }
+
+    pair_helpers::commit_request(db, &request).await?;
+    Ok(json!({"status": "paired"}))
+}
+
+pub async fn reject_pairing_request(
+    id: i64,
+    db: &Database,
+    client: &PeerClient,
+) -> Result<Value, Error> {
+    let request = db
+        .get_pairing_request(id)
+        .await?
+        .ok_or(Error::Generic("No such pairing request".into()))?;
+    db.delete_pairing_request(request.id).await?;
+
+    // Telling the other side is best effort
+    let pair_args = pair_helpers::build_pair_args(None, None).await?;
+    pair_helpers::send_pair_message(
+        client,
+        &request.ip,
+        request.port as u16,
+        &request.pub_key,
+        "pair/reject",
+        &pair_args,
+    )
+    .await
+    .map(|_| ())
+    .unwrap_or_else(|err| log::error!("{}", err));
+    log::info!("Rejected pairing with {}", &request.hostname);
+
+    Ok(json!({"status": "rejected"}))
 }
 
 mod scan_helpers {
     use serde::Deserialize;
     use serde_json::Value;
 
+    use super::echo_helpers;
+    use crate::utils::encryption::verify_message;
+
     #[derive(Debug, Deserialize)]
     pub struct ScanPeerResponse {
         pub success: bool,
         pub data: Value,
     }
+
+    // The host has to sign our challenge with the key it advertises
+    pub async fn verify_echo(challenge: &str, data: &Value) -> bool {
+        let field = |name: &str| data[name].as_str().unwrap_or("").to_owned();
+        verify_message(
+            &field("verify_key"),
+            &field("signature"),
+            &echo_helpers::signed_content(challenge, data),
+        )
+        .await
+        .is_ok()
+    }
 }
 
 pub async fn scan(
     potential_peer_list: web::Data<Arc<Mutex<Vec<String>>>>,
+    db: &Database,
+    client: &PeerClient,
 ) -> Result<Value, Error> {
     // Send ping message to the multicast group
-    send_multicast_msg(get_digest("resk").await.as_str()).await?;
+    let probe = Beacon::new(BEACON_PROBE).await?;
+    send_multicast_msg(&probe.to_string()).await?;
     sleep(Duration::from_secs(3)).await;
 
     // lock shared data
     let mut data = potential_peer_list.lock().await;
-    let ip_list = data.clone();
+    let host_list = data.clone();
     data.clear();
 
     // send echo to discovered hosts
+    let client = client.unpinned()?;
     let mut handles = Vec::new();
-    for ip in ip_list {
+    for host in host_list {
+        let challenge = generate_nonce()?;
+        let client = client.clone();
         let handle = tokio::spawn(async move {
-            let client = Client::builder()
-                .timeout(Duration::from_secs(3))
-                .build()
-                .ok()?;
-            let host = format!("http://{}:9898/echo", ip);
-            let response = client.get(&host).send().await;
-            response.ok()?.text().await.ok()
+            let url = format!("https://{}/echo?challenge={}", host, challenge);
+            let response = client.get(&url).send().await.ok()?;
+            let cert_key = get_response_pub_key(&response);
+            Some((host, challenge, cert_key, response.text().await.ok()?))
         });
 
         handles.push(handle);
@@ -103,12 +666,436 @@ pub async fn scan(
     // Parse results
     let mut result = Vec::new();
     for handle in handles {
-        if let Ok(Some(data)) = handle.await {
+        if let Ok(Some((host, challenge, cert_key, data))) = handle.await {
             let response: scan_helpers::ScanPeerResponse =
-                serde_json::from_str(&data)?;
+                match serde_json::from_str(&data) {
+                    Ok(response) => response,
+                    Err(err) => {
+                        log::info!("Host {} sent a bad echo: {}", host, err);
+                        continue;
+                    }
+                };
+
+            // The host has to own the key it advertises
+            let cert_key = match cert_key {
+                Some(cert_key)
+                    if response.data.get("verify_key")
+                        == Some(&Value::from(cert_key.clone())) =>
+                {
+                    cert_key
+                }
+                _ => {
+                    log::info!("Host {} sent a key it doesn't hold", host);
+                    continue;
+                }
+            };
+            if !scan_helpers::verify_echo(&challenge, &response.data).await {
+                log::info!("Host {} failed the echo challenge", host);
+                continue;
+            }
+            if db.is_blocked(&get_fingerprint(&cert_key)?).await? {
+                log::info!("Skipping blocked host {}", host);
+                continue;
+            }
             result.push(response.data.clone());
         }
     }
 
     Ok(json!({"ip_list": result}))
 }
+
+pub async fn rotate_keys(
+    db: &Database,
+    client: &PeerClient,
+    channels: &PeerChannels,
+) -> Result<Value, Error> {
+    let keys = NodeKeys::generate()?;
+
+    // Old key vouches for the new one, new key proves it is held
+    let mut args = HandoverArgs {
+        old_pub_key: get_verify_key()?,
+        new_pub_key: keys.verify_key.clone(),
+        new_agreement_key: keys.agreement_pub_key.clone(),
+        timestamp: get_timestamp(),
+        nonce: generate_nonce()?,
+        signature: String::new(),
+        new_signature: String::new(),
+    };
+    args.signature = sign_message(&args.signed_content()).await?;
+    args.new_signature = keys.sign(&args.signed_content())?;
+
+    // A peer that misses the handover is left with a key that no longer
+    // exists, so nothing changes unless every peer answers first
+    let peers = db.get_peers().await?;
+    let mut unreachable = Vec::new();
+    for peer in &peers {
+        let port = peer.port as u16;
+        let result =
+            reach_peer(&client.get(&peer.pub_key)?, &peer.ip, port).await;
+        if let Err(err) = result {
+            log::info!("Peer {} not reachable: {}", &peer.peer_id, err);
+            unreachable.push(peer.peer_id.clone());
+        }
+    }
+    if !unreachable.is_empty() {
+        return Err(Error::Generic(
+            format!(
+                "Key not rotated, peers not reachable: {}",
+                unreachable.join(", ")
+            )
+            .into(),
+        ));
+    }
+
+    // Peers have to learn the new key while the old one is still ours
+    let mut notified = Vec::new();
+    let mut failed = Vec::new();
+    for peer in peers {
+        // Sockets opened with the old key are of no use after the rotation
+        channels.close_peer(&peer.peer_id).await;
+        let result = pair_helpers::send_pair_message(
+            client,
+            &peer.ip,
+            peer.port as u16,
+            &peer.pub_key,
+            "handover",
+            &args,
+        )
+        .await;
+        match result {
+            Ok(_) => notified.push(peer),
+            Err(err) => {
+                log::info!("Failed to hand over to {}: {}", &peer.ip, err);
+                failed.push(peer.peer_id);
+            }
+        }
+    }
+
+    if !failed.is_empty() {
+        let stranded = hand_back(&keys, &args, &notified).await?;
+        let mut msg =
+            format!("Key not rotated, handover failed: {}", failed.join(", "));
+        if !stranded.is_empty() {
+            msg += &format!(
+                ", still on the discarded key: {}",
+                stranded.join(", ")
+            );
+        }
+        return Err(Error::Generic(msg.into()));
+    }
+
+    keys.install()?;
+    log::info!("Rotated node key, {} peers notified", notified.len());
+
+    let notified: Vec<String> =
+        notified.into_iter().map(|peer| peer.peer_id).collect();
+    Ok(json!({
+        "pub_key": args.new_pub_key,
+        "notified": notified,
+    }))
+}
+
+// Moves peers that already took the new key back to the current one.
+// Returns the ones that couldn't be reached, they have to pair again.
+async fn hand_back(
+    keys: &NodeKeys,
+    handover: &HandoverArgs,
+    notified: &[peer::Model],
+) -> Result<Vec<String>, Error> {
+    let agreement_key = fs::read(get_agreement_pub_key_path())?;
+    let mut args = HandoverArgs {
+        old_pub_key: handover.new_pub_key.clone(),
+        new_pub_key: handover.old_pub_key.clone(),
+        new_agreement_key: URL_SAFE_NO_PAD.encode(agreement_key),
+        timestamp: get_timestamp(),
+        nonce: generate_nonce()?,
+        signature: String::new(),
+        new_signature: String::new(),
+    };
+    args.signature = keys.sign(&args.signed_content())?;
+    args.new_signature = sign_message(&args.signed_content()).await?;
+
+    // Those peers only accept the new key now
+    let mut stranded = Vec::new();
+    for peer in notified {
+        let result = pair_helpers::post_pair_message(
+            &keys.client(&peer.pub_key)?,
+            &peer.ip,
+            peer.port as u16,
+            "handover",
+            &args,
+        )
+        .await;
+        if let Err(err) = result {
+            log::error!("Failed to hand back to {}: {}", &peer.ip, err);
+            stranded.push(peer.peer_id.clone());
+        }
+    }
+    Ok(stranded)
+}
+
+pub async fn verify_handover_args(
+    args: &HandoverArgs,
+    peer_id: Option<String>,
+    identity: Option<&PeerIdentity>,
+) -> bool {
+    // Only the current holder of the old key may hand it over
+    identity.map(|identity| &identity.0) == Some(&args.old_pub_key)
+        && peer_id.is_some()
+        && peer_id == get_fingerprint(&args.old_pub_key).ok()
+        && is_fresh(args.timestamp)
+        && verify_message(
+            &args.old_pub_key,
+            &args.signature,
+            &args.signed_content(),
+        )
+        .await
+        .is_ok()
+        && verify_message(
+            &args.new_pub_key,
+            &args.new_signature,
+            &args.signed_content(),
+        )
+        .await
+        .is_ok()
+}
+
+pub async fn handover(
+    args: &HandoverArgs,
+    nonce_cache: web::Data<Arc<Mutex<NonceCache>>>,
+    db: &Database,
+    client: &PeerClient,
+    channels: &PeerChannels,
+) -> Result<Value, Error> {
+    let peer = db
+        .get_peer(&get_fingerprint(&args.old_pub_key)?)
+        .await?
+        .ok_or(Error::Generic("Unknown peer".into()))?;
+    let new_peer_id = get_fingerprint(&args.new_pub_key)?;
+    if db.is_blocked(&new_peer_id).await? {
+        return Err(Error::Generic("New key is blocked".into()));
+    }
+    if db.get_peer(&new_peer_id).await?.is_some() {
+        return Err(Error::Generic("New key is already paired".into()));
+    }
+    if !nonce_cache
+        .lock()
+        .await
+        .check_and_insert(&args.nonce, args.timestamp)
+    {
+        return Err(Error::Generic("Stale or replayed message".into()));
+    }
+
+    db.rotate_peer_key(peer, &args.new_pub_key, &args.new_agreement_key)
+        .await?;
+    client.forget(&args.old_pub_key);
+    channels
+        .close_peer(&get_fingerprint(&args.old_pub_key)?)
+        .await;
+    log::info!(
+        "Peer {} rotated its key",
+        get_fingerprint(&args.old_pub_key)?
+    );
+
+    Ok(json!("OK"))
+}
+
+pub async fn remove_peer(
+    peer_id: &String,
+    block: bool,
+    args: &RemovePeerArgs,
+    db: &Database,
+    client: &PeerClient,
+    channels: &PeerChannels,
+) -> Result<Value, Error> {
+    let peer = db.get_peer(peer_id).await?;
+    if peer.is_none() && !block {
+        return Err(Error::Generic("Unknown peer".into()));
+    }
+
+    // Telling the other side is best effort
+    let mut notified = false;
+    if let (Some(peer), true) = (&peer, args.notify) {
+        let mut unpaired_args = UnpairedArgs {
+            peer_id: peer.peer_id.clone(),
+            timestamp: get_timestamp(),
+            nonce: generate_nonce()?,
+            signature: String::new(),
+        };
+        unpaired_args.signature =
+            sign_message(&unpaired_args.signed_content()).await?;
+        notified = pair_helpers::send_pair_message(
+            client,
+            &peer.ip,
+            peer.port as u16,
+            &peer.pub_key,
+            "unpaired",
+            &unpaired_args,
+        )
+        .await
+        .map_err(|err| log::info!("Failed to notify {}: {}", peer_id, err))
+        .is_ok();
+    }
+
+    db.delete_peer(peer_id).await?;
+    if let Some(peer) = &peer {
+        client.forget(&peer.pub_key);
+    }
+    channels.close_peer(peer_id).await;
+    if block {
+        let hostname = peer.map(|peer| peer.hostname).unwrap_or_default();
+        db.block_peer(peer_id, &hostname).await?;
+        log::info!("Revoked peer {}", peer_id);
+    } else {
+        log::info!("Unpaired peer {}", peer_id);
+    }
+
+    let status = if block { "revoked" } else { "unpaired" };
+    Ok(json!({"status": status, "notified": notified}))
+}
+
+pub async fn blocked_peers(db: &Database) -> Result<Value, Error> {
+    let blocked_peers: Vec<Value> = db
+        .get_blocked_peers()
+        .await?
+        .into_iter()
+        .map(|blocked_peer| {
+            json!({
+                "peer_id": blocked_peer.peer_id,
+                "hostname": blocked_peer.hostname,
+                "created_at": blocked_peer.created_at,
+            })
+        })
+        .collect();
+    Ok(json!({"blocked_peers": blocked_peers}))
+}
+
+pub async fn unblock_peer(
+    peer_id: &String,
+    db: &Database,
+) -> Result<Value, Error> {
+    db.unblock_peer(peer_id).await?;
+    log::info!("Unblocked peer {}", peer_id);
+    Ok(json!({"status": "unblocked"}))
+}
+
+pub async fn verify_unpaired_args(
+    args: &UnpairedArgs,
+    peer_id: Option<String>,
+    identity: &PeerIdentity,
+    nonce_cache: web::Data<Arc<Mutex<NonceCache>>>,
+) -> bool {
+    // The nonce is spent last, so a forged message can't burn it
+    peer_id.is_some()
+        && peer_id == get_fingerprint(&identity.0).ok()
+        && get_node_id().ok().as_ref() == Some(&args.peer_id)
+        && verify_message(&identity.0, &args.signature, &args.signed_content())
+            .await
+            .is_ok()
+        && nonce_cache
+            .lock()
+            .await
+            .check_and_insert(&args.nonce, args.timestamp)
+}
+
+pub async fn unpaired(
+    identity: &PeerIdentity,
+    db: &Database,
+    client: &PeerClient,
+    channels: &PeerChannels,
+) -> Result<Option<Value>, Error> {
+    let sender_id = get_fingerprint(&identity.0)?;
+    if db.get_peer(&sender_id).await?.is_none() {
+        return Ok(None);
+    }
+    db.delete_peer(&sender_id).await?;
+    client.forget(&identity.0);
+    channels.close_peer(&sender_id).await;
+    log::info!("Unpaired by peer {}", &sender_id);
+
+    Ok(Some(json!("OK")))
+}
+
+pub async fn peers(db: &Database) -> Result<PeerListResponse, Error> {
+    let peers = db.get_peers().await?.into_iter().map(Into::into).collect();
+    Ok(PeerListResponse { peers })
+}
+
+pub async fn get_peer(
+    peer_id: &str,
+    db: &Database,
+) -> Result<Option<PeerResponse>, Error> {
+    Ok(db.get_peer(peer_id).await?.map(Into::into))
+}
+
+pub async fn update_peer(
+    peer_id: &str,
+    args: &UpdatePeerArgs,
+    db: &Database,
+) -> Result<Option<PeerResponse>, Error> {
+    let peer = match db.get_peer(peer_id).await? {
+        Some(peer) => peer,
+        None => return Ok(None),
+    };
+
+    let changes = PeerChanges {
+        nickname: args.nickname.as_ref().map(|nickname| {
+            let nickname = nickname.trim();
+            (!nickname.is_empty()).then(|| nickname.to_owned())
+        }),
+        ip: args.ip.clone(),
+        port: args.port,
+        enabled: args.enabled,
+        trusted: args.trusted,
+    };
+    let peer = db.update_peer(peer, changes).await?;
+    db.add_peer_address(&peer, &peer.ip, peer.port as u16)
+        .await?;
+    log::info!("Updated peer {}", peer_id);
+
+    Ok(Some(peer.into()))
+}
+
+pub async fn status(db: &Database) -> Result<Value, Error> {
+    let config = get_config();
+    Ok(json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "node_id": get_node_id()?,
+        "hostname": get_hostname()?.to_string_lossy(),
+        "data_dir": get_data_dir(),
+        "node_port": config.node_port,
+        "control_port": config.control_port,
+        "peers": db.get_peers().await?.len(),
+        "pairing_requests": db.get_pairing_requests(INCOMING).await?.len(),
+    }))
+}
+
+pub async fn keys() -> Result<Value, Error> {
+    let agreement_key_bytes = fs::read(get_agreement_pub_key_path().as_str())?;
+    Ok(json!({
+        "node_id": get_node_id()?,
+        "verify_key": get_verify_key()?,
+        "agreement_key": URL_SAFE_NO_PAD.encode(agreement_key_bytes),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::pair_helpers::{commit_sas_nonce, matches_sas_commitment};
+    use crate::utils::encryption::generate_nonce;
+
+    #[test]
+    fn revealed_nonce_matches_its_commitment() {
+        let sas_nonce = generate_nonce().unwrap();
+        let commitment = commit_sas_nonce(&sas_nonce);
+        assert!(matches_sas_commitment(Some(&commitment), &sas_nonce));
+    }
+
+    #[test]
+    fn other_nonce_is_rejected() {
+        let commitment = commit_sas_nonce(&generate_nonce().unwrap());
+        let sas_nonce = generate_nonce().unwrap();
+        assert!(!matches_sas_commitment(Some(&commitment), &sas_nonce));
+        assert!(!matches_sas_commitment(None, &sas_nonce));
+    }
+}
@@ -1,7 +1,15 @@
-use crate::connect::controllers::{self, echo_helpers::get_local_ip};
-use crate::utils::general::{get_remote_ip, Response};
-use actix_web::{get, post, web, HttpRequest, Responder};
-use serde::Deserialize;
+use crate::connect::controllers;
+use crate::entity::peer;
+use crate::share::controllers::NonceCache;
+use crate::utils::channel::PeerChannels;
+use crate::utils::db::Database;
+use crate::utils::general::{
+    get_peer_id, get_remote_ip, is_authorized, Response,
+};
+use crate::utils::tls::{PeerClient, PeerIdentity};
+use actix_web::{delete, get, patch, post, web, HttpRequest, Responder};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
@@ -10,22 +18,161 @@ pub struct ConnectPeerArgs {
     pub pub_key: String,
     pub hostname: String,
     pub ip: String,
+    pub port: u16,
+    pub agreement_key: String,
+}
+
+#[derive(Deserialize)]
+pub struct EchoArgs {
+    pub challenge: String,
+}
+
+#[derive(Deserialize)]
+pub struct ConfirmPairingArgs {
+    pub sas: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PairArgs {
+    pub pub_key: String,
+    pub agreement_key: String,
+    pub hostname: String,
+    pub ip: String,
+    pub port: u16,
+    pub timestamp: u64,
+    pub nonce: String,
+    // Commitment to the requester's code nonce, sent with the request
+    #[serde(default)]
+    pub sas_commitment: Option<String>,
+    // The nonce itself, revealed once the other side sent its own
+    #[serde(default)]
+    pub sas_nonce: Option<String>,
+    pub signature: String,
+}
+
+impl PairArgs {
+    // Content covered by the sender's signature
+    pub fn signed_content(&self) -> String {
+        format!(
+            "{}.{}.{}.{}.{}.{}.{}.{}.{}",
+            self.pub_key,
+            self.agreement_key,
+            self.hostname,
+            self.ip,
+            self.port,
+            self.timestamp,
+            self.nonce,
+            self.sas_commitment.as_deref().unwrap_or(""),
+            self.sas_nonce.as_deref().unwrap_or("")
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct HandoverArgs {
+    pub old_pub_key: String,
+    pub new_pub_key: String,
+    pub new_agreement_key: String,
+    pub timestamp: u64,
+    pub nonce: String,
+    pub signature: String,
+    pub new_signature: String,
+}
+
+impl HandoverArgs {
+    // Content covered by both the old and the new key
+    pub fn signed_content(&self) -> String {
+        format!(
+            "{}.{}.{}.{}.{}",
+            self.old_pub_key,
+            self.new_pub_key,
+            self.new_agreement_key,
+            self.timestamp,
+            self.nonce
+        )
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RemovePeerArgs {
+    #[serde(default)]
+    pub notify: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UnpairedArgs {
+    pub peer_id: String,
+    pub timestamp: u64,
+    pub nonce: String,
+    pub signature: String,
+}
+
+impl UnpairedArgs {
+    // Content covered by the sender's signature
+    pub fn signed_content(&self) -> String {
+        format!("{}.{}.{}", self.peer_id, self.timestamp, self.nonce)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct UpdatePeerArgs {
+    // Empty string clears the nickname
+    pub nickname: Option<String>,
+    pub ip: Option<String>,
+    pub port: Option<u16>,
+    pub enabled: Option<bool>,
+    pub trusted: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub struct PeerResponse {
+    pub peer_id: String,
+    pub nickname: Option<String>,
+    pub hostname: String,
+    pub ip: String,
+    pub port: u16,
+    pub pub_key: String,
+    pub enabled: bool,
+    pub trusted: bool,
+    pub last_seen: Option<i64>,
+    pub created_at: i64,
+}
+
+impl From<peer::Model> for PeerResponse {
+    fn from(peer: peer::Model) -> Self {
+        Self {
+            peer_id: peer.peer_id,
+            nickname: peer.nickname,
+            hostname: peer.hostname,
+            ip: peer.ip,
+            port: peer.port as u16,
+            pub_key: peer.pub_key,
+            enabled: peer.enabled,
+            trusted: peer.trusted,
+            last_seen: peer.last_seen,
+            created_at: peer.created_at,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct PeerListResponse {
+    pub peers: Vec<PeerResponse>,
 }
 
 #[post("/connect_peer")]
 pub async fn connect_peer(
     req: HttpRequest,
     data: web::Json<ConnectPeerArgs>,
+    db: web::Data<Database>,
+    client: web::Data<PeerClient>,
 ) -> impl Responder {
-    // TODO replace it somehow
-    if get_remote_ip(&req).await != get_local_ip().await
-        && get_remote_ip(&req).await != "127.0.0.1"
-    {
-        return Response::failure(403, "Forbiden".to_string());
+    if !is_authorized(&req) {
+        return Response::failure(401, "Unauthorized".to_string());
     }
 
     let args = data.into_inner();
-    let response = controllers::connect_peer(&args).await;
+    let response = controllers::connect_peer(&args, &db, &client).await;
     match response {
         Ok(data) => Response::success(data),
         Err(e) => Response::failure(500, e.to_string()),
@@ -33,8 +180,13 @@ pub async fn connect_peer(
 }
 
 #[get("/echo")]
-pub async fn echo() -> impl Responder {
-    let response = controllers::echo().await;
+pub async fn echo(query: web::Query<EchoArgs>) -> impl Responder {
+    let args = query.into_inner();
+    if args.challenge.len() > 64 {
+        return Response::failure(400, "Challenge too long".to_string());
+    }
+
+    let response = controllers::echo(&args).await;
     match response {
         Ok(data) => Response::success(data),
         Err(e) => Response::failure(500, e.to_string()),
@@ -45,15 +197,531 @@ pub async fn echo() -> impl Responder {
 pub async fn scan(
     req: HttpRequest,
     potential_peer_list: web::Data<Arc<Mutex<Vec<String>>>>,
+    db: web::Data<Database>,
+    client: web::Data<PeerClient>,
+) -> impl Responder {
+    if !is_authorized(&req) {
+        return Response::failure(401, "Unauthorized".to_string());
+    }
+
+    let response = controllers::scan(potential_peer_list, &db, &client).await;
+    match response {
+        Ok(data) => Response::success(data),
+        Err(e) => Response::failure(500, e.to_string()),
+    }
+}
+
+#[post("/pair")]
+pub async fn pair(
+    req: HttpRequest,
+    data: web::Json<PairArgs>,
+    db: web::Data<Database>,
+) -> impl Responder {
+    let args = data.into_inner();
+    if !controllers::verify_pair_args(
+        &args,
+        get_peer_id(&req),
+        req.conn_data::<PeerIdentity>(),
+    )
+    .await
+    {
+        return Response::failure(
+            403,
+            "Failed to verify signature".to_string(),
+        );
+    }
+
+    match controllers::has_room_for_pairing_request(&args, &db).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return Response::failure(
+                429,
+                "Too many pending pairing requests".to_string(),
+            )
+        }
+        Err(e) => return Response::failure(500, e.to_string()),
+    }
+
+    let response =
+        controllers::pair(&args, &get_remote_ip(&req).await, &db).await;
+    match response {
+        Ok(data) => Response::success(data),
+        Err(e) => Response::failure(500, e.to_string()),
+    }
+}
+
+#[post("/pair/accept")]
+pub async fn pair_accept(
+    req: HttpRequest,
+    data: web::Json<PairArgs>,
+    db: web::Data<Database>,
+) -> impl Responder {
+    let args = data.into_inner();
+    if !controllers::verify_pair_args(
+        &args,
+        get_peer_id(&req),
+        req.conn_data::<PeerIdentity>(),
+    )
+    .await
+    {
+        return Response::failure(
+            403,
+            "Failed to verify signature".to_string(),
+        );
+    }
+
+    let response = controllers::pair_accept(&args, &db).await;
+    match response {
+        Ok(data) => Response::success(data),
+        Err(e) => Response::failure(500, e.to_string()),
+    }
+}
+
+#[post("/pair/reveal")]
+pub async fn pair_reveal(
+    req: HttpRequest,
+    data: web::Json<PairArgs>,
+    db: web::Data<Database>,
+) -> impl Responder {
+    let args = data.into_inner();
+    if !controllers::verify_pair_args(
+        &args,
+        get_peer_id(&req),
+        req.conn_data::<PeerIdentity>(),
+    )
+    .await
+    {
+        return Response::failure(
+            403,
+            "Failed to verify signature".to_string(),
+        );
+    }
+
+    let response = controllers::pair_reveal(&args, &db).await;
+    match response {
+        Ok(data) => Response::success(data),
+        Err(e) => Response::failure(500, e.to_string()),
+    }
+}
+
+#[post("/pair/reject")]
+pub async fn pair_reject(
+    req: HttpRequest,
+    data: web::Json<PairArgs>,
+    db: web::Data<Database>,
+) -> impl Responder {
+    let args = data.into_inner();
+    if !controllers::verify_pair_args(
+        &args,
+        get_peer_id(&req),
+        req.conn_data::<PeerIdentity>(),
+    )
+    .await
+    {
+        return Response::failure(
+            403,
+            "Failed to verify signature".to_string(),
+        );
+    }
+
+    let response = controllers::pair_reject(&args, &db).await;
+    match response {
+        Ok(data) => Response::success(data),
+        Err(e) => Response::failure(500, e.to_string()),
+    }
+}
+
+#[get("/pairing_requests")]
+pub async fn pairing_requests(
+    req: HttpRequest,
+    db: web::Data<Database>,
+) -> impl Responder {
+    if !is_authorized(&req) {
+        return Response::failure(401, "Unauthorized".to_string());
+    }
+
+    let response = controllers::pairing_requests(&db).await;
+    match response {
+        Ok(data) => Response::success(data),
+        Err(e) => Response::failure(500, e.to_string()),
+    }
+}
+
+#[post("/pairing_requests/{id}/accept")]
+pub async fn accept_pairing_request(
+    req: HttpRequest,
+    id: web::Path<i64>,
+    data: web::Json<ConfirmPairingArgs>,
+    db: web::Data<Database>,
+    client: web::Data<PeerClient>,
+) -> impl Responder {
+    if !is_authorized(&req) {
+        return Response::failure(401, "Unauthorized".to_string());
+    }
+
+    let args = data.into_inner();
+    let response = controllers::accept_pairing_request(
+        id.into_inner(),
+        &args,
+        &db,
+        &client,
+    )
+    .await;
+    match response {
+        Ok(data) => Response::success(data),
+        Err(e) => Response::failure(500, e.to_string()),
+    }
+}
+
+#[post("/pairing_requests/{id}/confirm")]
+pub async fn confirm_pairing_request(
+    req: HttpRequest,
+    id: web::Path<i64>,
+    data: web::Json<ConfirmPairingArgs>,
+    db: web::Data<Database>,
+) -> impl Responder {
+    if !is_authorized(&req) {
+        return Response::failure(401, "Unauthorized".to_string());
+    }
+
+    let args = data.into_inner();
+    let response =
+        controllers::confirm_pairing_request(id.into_inner(), &args, &db).await;
+    match response {
+        Ok(data) => Response::success(data),
+        Err(e) => Response::failure(500, e.to_string()),
+    }
+}
+
+#[post("/pairing_requests/{id}/reject")]
+pub async fn reject_pairing_request(
+    req: HttpRequest,
+    id: web::Path<i64>,
+    db: web::Data<Database>,
+    client: web::Data<PeerClient>,
+) -> impl Responder {
+    if !is_authorized(&req) {
+        return Response::failure(401, "Unauthorized".to_string());
+    }
+
+    let response =
+        controllers::reject_pairing_request(id.into_inner(), &db, &client)
+            .await;
+    match response {
+        Ok(data) => Response::success(data),
+        Err(e) => Response::failure(500, e.to_string()),
+    }
+}
+
+#[post("/rotate_keys")]
+pub async fn rotate_keys(
+    req: HttpRequest,
+    db: web::Data<Database>,
+    client: web::Data<PeerClient>,
+    channels: web::Data<PeerChannels>,
+) -> impl Responder {
+    if !is_authorized(&req) {
+        return Response::failure(401, "Unauthorized".to_string());
+    }
+
+    let response = controllers::rotate_keys(&db, &client, &channels).await;
+    match response {
+        Ok(data) => Response::success(data),
+        Err(e) => Response::failure(500, e.to_string()),
+    }
+}
+
+#[post("/handover")]
+pub async fn handover(
+    req: HttpRequest,
+    data: web::Json<HandoverArgs>,
+    nonce_cache: web::Data<Arc<Mutex<NonceCache>>>,
+    db: web::Data<Database>,
+    client: web::Data<PeerClient>,
+    channels: web::Data<PeerChannels>,
 ) -> impl Responder {
-    // TODO replace it somehow
-    if get_remote_ip(&req).await != get_local_ip().await
-        && get_remote_ip(&req).await != "127.0.0.1"
+    let args = data.into_inner();
+    if !controllers::verify_handover_args(
+        &args,
+        get_peer_id(&req),
+        req.conn_data::<PeerIdentity>(),
+    )
+    .await
     {
-        return Response::failure(403, "Forbiden".to_string());
+        return Response::failure(
+            403,
+            "Failed to verify signature".to_string(),
+        );
+    }
+
+    let response =
+        controllers::handover(&args, nonce_cache, &db, &client, &channels)
+            .await;
+    match response {
+        Ok(data) => Response::success(data),
+        Err(e) => Response::failure(500, e.to_string()),
+    }
+}
+
+#[post("/peers/{peer_id}/unpair")]
+pub async fn unpair_peer(
+    req: HttpRequest,
+    peer_id: web::Path<String>,
+    data: web::Json<RemovePeerArgs>,
+    db: web::Data<Database>,
+    client: web::Data<PeerClient>,
+    channels: web::Data<PeerChannels>,
+) -> impl Responder {
+    if !is_authorized(&req) {
+        return Response::failure(401, "Unauthorized".to_string());
+    }
+
+    let args = data.into_inner();
+    let response = controllers::remove_peer(
+        &peer_id.into_inner(),
+        false,
+        &args,
+        &db,
+        &client,
+        &channels,
+    )
+    .await;
+    match response {
+        Ok(data) => Response::success(data),
+        Err(e) => Response::failure(500, e.to_string()),
+    }
+}
+
+#[post("/peers/{peer_id}/revoke")]
+pub async fn revoke_peer(
+    req: HttpRequest,
+    peer_id: web::Path<String>,
+    data: web::Json<RemovePeerArgs>,
+    db: web::Data<Database>,
+    client: web::Data<PeerClient>,
+    channels: web::Data<PeerChannels>,
+) -> impl Responder {
+    if !is_authorized(&req) {
+        return Response::failure(401, "Unauthorized".to_string());
+    }
+
+    let args = data.into_inner();
+    let response = controllers::remove_peer(
+        &peer_id.into_inner(),
+        true,
+        &args,
+        &db,
+        &client,
+        &channels,
+    )
+    .await;
+    match response {
+        Ok(data) => Response::success(data),
+        Err(e) => Response::failure(500, e.to_string()),
+    }
+}
+
+#[get("/blocked_peers")]
+pub async fn blocked_peers(
+    req: HttpRequest,
+    db: web::Data<Database>,
+) -> impl Responder {
+    if !is_authorized(&req) {
+        return Response::failure(401, "Unauthorized".to_string());
+    }
+
+    let response = controllers::blocked_peers(&db).await;
+    match response {
+        Ok(data) => Response::success(data),
+        Err(e) => Response::failure(500, e.to_string()),
+    }
+}
+
+#[post("/blocked_peers/{peer_id}/unblock")]
+pub async fn unblock_peer(
+    req: HttpRequest,
+    peer_id: web::Path<String>,
+    db: web::Data<Database>,
+) -> impl Responder {
+    if !is_authorized(&req) {
+        return Response::failure(401, "Unauthorized".to_string());
+    }
+
+    let response = controllers::unblock_peer(&peer_id.into_inner(), &db).await;
+    match response {
+        Ok(data) => Response::success(data),
+        Err(e) => Response::failure(500, e.to_string()),
+    }
+}
+
+#[post("/unpaired")]
+pub async fn unpaired(
+    req: HttpRequest,
+    data: web::Json<UnpairedArgs>,
+    nonce_cache: web::Data<Arc<Mutex<NonceCache>>>,
+    db: web::Data<Database>,
+    client: web::Data<PeerClient>,
+    channels: web::Data<PeerChannels>,
+) -> impl Responder {
+    // Peers authenticate with their key during the TLS handshake
+    let identity = match req.conn_data::<PeerIdentity>() {
+        Some(identity) => identity.clone(),
+        None => {
+            return Response::failure(
+                403,
+                "Client certificate required".to_string(),
+            )
+        }
+    };
+
+    let args = data.into_inner();
+    if !controllers::verify_unpaired_args(
+        &args,
+        get_peer_id(&req),
+        &identity,
+        nonce_cache,
+    )
+    .await
+    {
+        return Response::failure(
+            403,
+            "Failed to verify signature".to_string(),
+        );
+    }
+
+    let response =
+        controllers::unpaired(&identity, &db, &client, &channels).await;
+    match response {
+        Ok(Some(data)) => Response::success(data),
+        Ok(None) => Response::failure(403, "Unknown peer".to_string()),
+        Err(e) => Response::failure(500, e.to_string()),
+    }
+}
+
+#[get("/peers")]
+pub async fn peers(
+    req: HttpRequest,
+    db: web::Data<Database>,
+) -> impl Responder {
+    if !is_authorized(&req) {
+        return Response::failure(401, "Unauthorized".to_string());
+    }
+
+    let response = controllers::peers(&db).await;
+    match response {
+        Ok(data) => Response::success(data),
+        Err(e) => Response::failure(500, e.to_string()),
+    }
+}
+
+#[get("/peers/{peer_id}")]
+pub async fn get_peer(
+    req: HttpRequest,
+    peer_id: web::Path<String>,
+    db: web::Data<Database>,
+) -> impl Responder {
+    if !is_authorized(&req) {
+        return Response::failure(401, "Unauthorized".to_string());
+    }
+
+    let response = controllers::get_peer(&peer_id.into_inner(), &db).await;
+    match response {
+        Ok(Some(data)) => Response::success(data),
+        Ok(None) => Response::failure(404, "Unknown peer".to_string()),
+        Err(e) => Response::failure(500, e.to_string()),
+    }
+}
+
+#[patch("/peers/{peer_id}")]
+pub async fn update_peer(
+    req: HttpRequest,
+    peer_id: web::Path<String>,
+    data: web::Json<UpdatePeerArgs>,
+    db: web::Data<Database>,
+) -> impl Responder {
+    if !is_authorized(&req) {
+        return Response::failure(401, "Unauthorized".to_string());
+    }
+
+    let args = data.into_inner();
+    if args
+        .nickname
+        .as_ref()
+        .is_some_and(|nickname| nickname.len() > 64)
+    {
+        return Response::failure(400, "Nickname too long".to_string());
+    }
+    if args
+        .ip
+        .as_ref()
+        .is_some_and(|ip| ip.parse::<IpAddr>().is_err())
+    {
+        return Response::failure(400, "Invalid IP address".to_string());
+    }
+
+    let response =
+        controllers::update_peer(&peer_id.into_inner(), &args, &db).await;
+    match response {
+        Ok(Some(data)) => Response::success(data),
+        Ok(None) => Response::failure(404, "Unknown peer".to_string()),
+        Err(e) => Response::failure(500, e.to_string()),
+    }
+}
+
+#[delete("/peers/{peer_id}")]
+pub async fn delete_peer(
+    req: HttpRequest,
+    peer_id: web::Path<String>,
+    query: web::Query<RemovePeerArgs>,
+    db: web::Data<Database>,
+    client: web::Data<PeerClient>,
+    channels: web::Data<PeerChannels>,
+) -> impl Responder {
+    if !is_authorized(&req) {
+        return Response::failure(401, "Unauthorized".to_string());
+    }
+
+    let peer_id = peer_id.into_inner();
+    match controllers::get_peer(&peer_id, &db).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return Response::failure(404, "Unknown peer".to_string()),
+        Err(e) => return Response::failure(500, e.to_string()),
+    }
+
+    let args = query.into_inner();
+    let response = controllers::remove_peer(
+        &peer_id, false, &args, &db, &client, &channels,
+    )
+    .await;
+    match response {
+        Ok(data) => Response::success(data),
+        Err(e) => Response::failure(500, e.to_string()),
+    }
+}
+
+#[get("/status")]
+pub async fn status(
+    req: HttpRequest,
+    db: web::Data<Database>,
+) -> impl Responder {
+    if !is_authorized(&req) {
+        return Response::failure(401, "Unauthorized".to_string());
+    }
+
+    let response = controllers::status(&db).await;
+    match response {
+        Ok(data) => Response::success(data),
+        Err(e) => Response::failure(500, e.to_string()),
+    }
+}
+
+#[get("/keys")]
+pub async fn keys(req: HttpRequest) -> impl Responder {
+    if !is_authorized(&req) {
+        return Response::failure(401, "Unauthorized".to_string());
     }
 
-    let response = controllers::scan(potential_peer_list).await;
+    let response = controllers::keys().await;
     match response {
         Ok(data) => Response::success(data),
         Err(e) => Response::failure(500, e.to_string()),
@@ -2,4 +2,8 @@
 
 pub mod prelude;
 
+pub mod blocked_peer;
+pub mod outbound_update;
+pub mod pairing_request;
 pub mod peer;
+pub mod peer_address;
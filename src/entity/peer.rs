@@ -7,9 +7,18 @@ use sea_orm::entity::prelude::*;
 pub struct Model {
     #[sea_orm(primary_key)]
     pub id: i64,
+    #[sea_orm(unique)]
     pub pub_key: String,
     pub hostname: String,
     pub ip: String,
+    pub agreement_key: Option<String>,
+    pub peer_id: String,
+    pub nickname: Option<String>,
+    pub enabled: bool,
+    pub last_seen: Option<i64>,
+    pub created_at: i64,
+    pub port: i32,
+    pub trusted: bool,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -1,3 +1,7 @@
 //! `SeaORM` Entity. Generated by sea-orm-codegen 0.10.6
 
+pub use super::blocked_peer::Entity as BlockedPeer;
+pub use super::outbound_update::Entity as OutboundUpdate;
+pub use super::pairing_request::Entity as PairingRequest;
 pub use super::peer::Entity as Peer;
+pub use super::peer_address::Entity as PeerAddress;
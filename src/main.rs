@@ -1,5 +1,8 @@
-use utils::controllers::run;
+use clap::Parser;
+use cli::{args::Cli, commands::execute};
+use std::process;
 
+mod cli;
 mod connect;
 mod entity;
 mod migration;
@@ -8,5 +11,8 @@ mod utils;
 
 #[actix_web::main]
 async fn main() {
-    run().await.unwrap_or_else(|err| eprintln!("{}", err));
+    if let Err(err) = execute(Cli::parse()).await {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
 }
@@ -0,0 +1,70 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(PairingRequest::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(PairingRequest::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(PairingRequest::Direction)
+                            .string()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(PairingRequest::PubKey)
+                            .string()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(PairingRequest::AgreementKey)
+                            .string()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(PairingRequest::Hostname)
+                            .string()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(PairingRequest::Ip).string().not_null())
+                    .col(
+                        ColumnDef::new(PairingRequest::CreatedAt)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(PairingRequest::Table).to_owned())
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum PairingRequest {
+    Table,
+    Id,
+    Direction,
+    PubKey,
+    AgreementKey,
+    Hostname,
+    Ip,
+    CreatedAt,
+}
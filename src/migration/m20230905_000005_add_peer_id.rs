@@ -0,0 +1,97 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ring::digest;
+use sea_orm_migration::prelude::*;
+use sea_orm_migration::sea_orm::ConnectionTrait;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Peer::Table)
+                    .add_column(
+                        ColumnDef::new(Peer::PeerId)
+                            .string()
+                            .not_null()
+                            .default(""),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        // Backfill IDs of already paired peers from their keys
+        let db = manager.get_connection();
+        let backend = manager.get_database_backend();
+        let select = Query::select()
+            .columns([Peer::Id, Peer::PubKey])
+            .from(Peer::Table)
+            .to_owned();
+        for row in db.query_all(backend.build(&select)).await? {
+            let id: i64 = row.try_get("", "id")?;
+            let pub_key: String = row.try_get("", "pub_key")?;
+            let peer_id = fingerprint(&pub_key)?;
+            let update = Query::update()
+                .table(Peer::Table)
+                .value(Peer::PeerId, peer_id)
+                .and_where(Expr::col(Peer::Id).eq(id))
+                .to_owned();
+            db.execute(backend.build(&update)).await?;
+        }
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-peer-peer_id")
+                    .table(Peer::Table)
+                    .col(Peer::PeerId)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_index(
+                Index::drop()
+                    .name("idx-peer-peer_id")
+                    .table(Peer::Table)
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Peer::Table)
+                    .drop_column(Peer::PeerId)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+// Frozen copy of the peer ID derivation at the time of this migration,
+// so later changes to the app helper can't alter what it writes
+fn fingerprint(pub_key: &str) -> Result<String, DbErr> {
+    let key_bytes = URL_SAFE_NO_PAD
+        .decode(pub_key)
+        .map_err(|err| DbErr::Migration(err.to_string()))?;
+    Ok(digest::digest(&digest::SHA256, &key_bytes)
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum Peer {
+    Table,
+    Id,
+    PubKey,
+    PeerId,
+}
@@ -0,0 +1,72 @@
+use sea_orm_migration::prelude::*;
+
+// Port of a stock install, assumed for rows stored before ports were
+const DEFAULT_NODE_PORT: u16 = 9898;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Peer::Table)
+                    .add_column(
+                        ColumnDef::new(Peer::Port)
+                            .integer()
+                            .not_null()
+                            .default(DEFAULT_NODE_PORT),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(PairingRequest::Table)
+                    .add_column(
+                        ColumnDef::new(PairingRequest::Port)
+                            .integer()
+                            .not_null()
+                            .default(DEFAULT_NODE_PORT),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(PairingRequest::Table)
+                    .drop_column(PairingRequest::Port)
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Peer::Table)
+                    .drop_column(Peer::Port)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum Peer {
+    Table,
+    Port,
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum PairingRequest {
+    Table,
+    Port,
+}
@@ -0,0 +1,101 @@
+use sea_orm_migration::prelude::*;
+use sea_orm_migration::sea_orm::{ConnectionTrait, Statement};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // SQLite alters one column per statement
+        let columns = [
+            ColumnDef::new(Peer::LastSeen).big_integer().to_owned(),
+            ColumnDef::new(Peer::CreatedAt)
+                .big_integer()
+                .not_null()
+                .default(0)
+                .to_owned(),
+            ColumnDef::new(Peer::Trusted)
+                .boolean()
+                .not_null()
+                .default(false)
+                .to_owned(),
+        ];
+        for mut column in columns {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Peer::Table)
+                        .add_column(&mut column)
+                        .to_owned(),
+                )
+                .await?;
+        }
+
+        // Pairing date of existing peers is unknown, start counting now
+        let db = manager.get_connection();
+        let backend = manager.get_database_backend();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs() as i64)
+            .unwrap_or(0);
+        let update = Query::update()
+            .table(Peer::Table)
+            .value(Peer::CreatedAt, now)
+            .to_owned();
+        db.execute(backend.build(&update)).await?;
+
+        // Older versions could store a key twice, keep the latest row
+        db.execute(Statement::from_string(
+            backend,
+            "DELETE FROM peer WHERE id NOT IN \
+             (SELECT MAX(id) FROM peer GROUP BY pub_key)"
+                .to_owned(),
+        ))
+        .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-peer-pub_key")
+                    .table(Peer::Table)
+                    .col(Peer::PubKey)
+                    .unique()
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_index(
+                Index::drop()
+                    .name("idx-peer-pub_key")
+                    .table(Peer::Table)
+                    .to_owned(),
+            )
+            .await?;
+        for column in [Peer::Trusted, Peer::CreatedAt, Peer::LastSeen] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Peer::Table)
+                        .drop_column(column)
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum Peer {
+    Table,
+    PubKey,
+    LastSeen,
+    CreatedAt,
+    Trusted,
+}
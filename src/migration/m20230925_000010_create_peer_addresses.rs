@@ -0,0 +1,109 @@
+use crate::utils::general::get_timestamp;
+use sea_orm_migration::prelude::*;
+use sea_orm_migration::sea_orm::ConnectionTrait;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(PeerAddress::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(PeerAddress::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(PeerAddress::PeerId)
+                            .integer()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(PeerAddress::Ip).string().not_null())
+                    .col(ColumnDef::new(PeerAddress::Port).integer().not_null())
+                    .col(ColumnDef::new(PeerAddress::LastSuccess).big_integer())
+                    .col(
+                        ColumnDef::new(PeerAddress::CreatedAt)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-peer_address-peer_id")
+                            .from(PeerAddress::Table, PeerAddress::PeerId)
+                            .to(Peer::Table, Peer::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx-peer_address-peer_id-ip-port")
+                    .table(PeerAddress::Table)
+                    .col(PeerAddress::PeerId)
+                    .col(PeerAddress::Ip)
+                    .col(PeerAddress::Port)
+                    .unique()
+                    .to_owned(),
+            )
+            .await?;
+
+        // Address known so far becomes the first one of each peer
+        let db = manager.get_connection();
+        let backend = manager.get_database_backend();
+        let insert = Query::insert()
+            .into_table(PeerAddress::Table)
+            .columns([
+                PeerAddress::PeerId,
+                PeerAddress::Ip,
+                PeerAddress::Port,
+                PeerAddress::CreatedAt,
+            ])
+            .select_from(
+                Query::select()
+                    .columns([Peer::Id, Peer::Ip, Peer::Port])
+                    .expr(Expr::val(get_timestamp() as i64))
+                    .from(Peer::Table)
+                    .to_owned(),
+            )
+            .map_err(|err| DbErr::Migration(err.to_string()))?
+            .to_owned();
+        db.execute(backend.build(&insert)).await?;
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(PeerAddress::Table).to_owned())
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum PeerAddress {
+    Table,
+    Id,
+    PeerId,
+    Ip,
+    Port,
+    LastSuccess,
+    CreatedAt,
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum Peer {
+    Table,
+    Id,
+    Ip,
+    Port,
+}
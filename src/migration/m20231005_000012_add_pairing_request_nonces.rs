@@ -0,0 +1,79 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(PairingRequest::Table)
+                    .add_column(
+                        ColumnDef::new(PairingRequest::SasNonce)
+                            .string()
+                            .not_null()
+                            .default(""),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(PairingRequest::Table)
+                    .add_column(
+                        ColumnDef::new(PairingRequest::PeerSasNonce).string(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(PairingRequest::Table)
+                    .add_column(
+                        ColumnDef::new(PairingRequest::SasCommitment).string(),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(PairingRequest::Table)
+                    .drop_column(PairingRequest::SasCommitment)
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(PairingRequest::Table)
+                    .drop_column(PairingRequest::PeerSasNonce)
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(PairingRequest::Table)
+                    .drop_column(PairingRequest::SasNonce)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum PairingRequest {
+    Table,
+    SasNonce,
+    PeerSasNonce,
+    SasCommitment,
+}
@@ -1,12 +1,38 @@
 pub use sea_orm_migration::prelude::*;
 
 pub mod m20220101_000001_create_peers;
+pub mod m20230820_000002_add_peer_agreement_key;
+pub mod m20230825_000003_create_pairing_requests;
+pub mod m20230901_000004_add_pairing_request_confirmation;
+pub mod m20230905_000005_add_peer_id;
+pub mod m20230910_000006_create_blocked_peers;
+pub mod m20230912_000007_add_peer_ports;
+pub mod m20230915_000008_add_peer_nickname;
+pub mod m20230920_000009_extend_peers;
+pub mod m20230925_000010_create_peer_addresses;
+pub mod m20231001_000011_create_outbound_updates;
+pub mod m20231005_000012_add_pairing_request_nonces;
 
 pub struct Migrator;
 
 #[async_trait::async_trait]
 impl MigratorTrait for Migrator {
     fn migrations() -> Vec<Box<dyn MigrationTrait>> {
-        vec![Box::new(m20220101_000001_create_peers::Migration)]
+        vec![
+            Box::new(m20220101_000001_create_peers::Migration),
+            Box::new(m20230820_000002_add_peer_agreement_key::Migration),
+            Box::new(m20230825_000003_create_pairing_requests::Migration),
+            Box::new(
+                m20230901_000004_add_pairing_request_confirmation::Migration,
+            ),
+            Box::new(m20230905_000005_add_peer_id::Migration),
+            Box::new(m20230910_000006_create_blocked_peers::Migration),
+            Box::new(m20230912_000007_add_peer_ports::Migration),
+            Box::new(m20230915_000008_add_peer_nickname::Migration),
+            Box::new(m20230920_000009_extend_peers::Migration),
+            Box::new(m20230925_000010_create_peer_addresses::Migration),
+            Box::new(m20231001_000011_create_outbound_updates::Migration),
+            Box::new(m20231005_000012_add_pairing_request_nonces::Migration),
+        ]
     }
 }
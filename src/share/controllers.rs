@@ -1,30 +1,255 @@
+use actix_web::web;
 use clipboard::{ClipboardContext, ClipboardProvider};
 use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::Mutex;
 
-use crate::share::routes::UpdateArgs;
+use crate::entity::peer;
+use crate::share::routes::{SendArgs, UpdateArgs};
+use crate::utils::communication::{update_peers, wake_outbound_queue};
+use crate::utils::config::get_config;
 use crate::utils::db::Database;
-use crate::utils::encryption::verify_message;
+use crate::utils::encryption::{
+    decrypt_message, get_content_hash, get_fingerprint, verify_message,
+};
 use crate::utils::error::Error;
+use crate::utils::general::{get_timestamp, is_fresh, MAX_MESSAGE_AGE};
+use crate::utils::tls::PeerIdentity;
 
-pub async fn update(args: &UpdateArgs) -> Result<Value, Error> {
+// Nonces of recently accepted updates, kept until they go stale
+#[derive(Default)]
+pub struct NonceCache {
+    nonces: HashMap<String, u64>,
+}
+
+impl NonceCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Returns false if the message is stale or was already seen
+    pub fn check_and_insert(&mut self, nonce: &String, timestamp: u64) -> bool {
+        if !is_fresh(timestamp) {
+            return false;
+        }
+
+        // Stale nonces are rejected by timestamp anyway
+        let now = get_timestamp();
+        self.nonces
+            .retain(|_, seen| now.abs_diff(*seen) <= MAX_MESSAGE_AGE);
+        if self.nonces.contains_key(nonce) {
+            return false;
+        }
+        self.nonces.insert(nonce.to_owned(), timestamp);
+        true
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn full(burst: f64, now: Instant) -> Self {
+        Self {
+            tokens: burst,
+            updated: now,
+        }
+    }
+
+    // Refills for the time since the last call, then takes one token
+    fn take(&mut self, now: Instant, rate: f64, burst: f64) -> bool {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.updated = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+// Per-peer token buckets throttling /update
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: HashMap<String, TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Returns false if the peer ran out of tokens
+    pub fn check(&mut self, peer_id: &str) -> bool {
+        let config = get_config();
+        let (rate, burst) = (config.update_rate, config.update_burst);
+        let now = Instant::now();
+        self.buckets
+            .entry(peer_id.to_owned())
+            .or_insert_with(|| TokenBucket::full(burst, now))
+            .take(now, rate, burst)
+    }
+}
+
+// Origin of the clipboard, so content applied from peers isn't sent back
+#[derive(Default)]
+pub struct ClipboardState {
+    last_applied: Option<String>,
+    message_ids: HashMap<String, u64>,
+    // Local content that looked like a secret, shared only on request
+    withheld: Option<String>,
+}
+
+impl ClipboardState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Returns false if the message was already applied
+    fn record_applied(&mut self, message_id: &String, content: &str) -> bool {
+        let now = get_timestamp();
+        self.message_ids
+            .retain(|_, seen| now.abs_diff(*seen) <= MAX_MESSAGE_AGE);
+        if self.message_ids.contains_key(message_id) {
+            return false;
+        }
+        self.message_ids.insert(message_id.to_owned(), now);
+        self.last_applied = Some(get_content_hash(content));
+        true
+    }
+
+    // Returns true once for content that came from a peer
+    pub fn take_applied(&mut self, content: &str) -> bool {
+        if self.last_applied.as_deref() == Some(&get_content_hash(content)) {
+            self.last_applied = None;
+            return true;
+        }
+        false
+    }
+
+    pub fn withhold(&mut self, content: Option<String>) {
+        self.withheld = content;
+    }
+}
+
+pub async fn share_withheld(
+    clipboard_state: web::Data<Arc<Mutex<ClipboardState>>>,
+    db: &Database,
+) -> Result<Value, Error> {
+    let content = clipboard_state
+        .lock()
+        .await
+        .withheld
+        .take()
+        .ok_or(Error::Generic("No withheld clipboard content".into()))?;
+    update_peers(db, content).await?;
+    log::info!("Shared withheld clipboard content on request");
+    Ok(json!({"status": "shared"}))
+}
+
+pub async fn send(args: &SendArgs, db: &Database) -> Result<Value, Error> {
+    update_peers(db, args.content.clone()).await?;
+    log::info!("Shared {} bytes on request", args.content.len());
+    Ok(json!({"status": "shared"}))
+}
+
+// Checks shared by /update and the push channel, failures come with the
+// HTTP status the sender decides whether to retry on
+pub async fn receive_update(
+    args: &UpdateArgs,
+    peer_id: Option<String>,
+    identity: &PeerIdentity,
+    nonce_cache: web::Data<Arc<Mutex<NonceCache>>>,
+    clipboard_state: web::Data<Arc<Mutex<ClipboardState>>>,
+    rate_limiter: web::Data<Arc<Mutex<RateLimiter>>>,
+    db: &Database,
+) -> Result<Value, (u16, String)> {
+    let peer = match peer_id {
+        Some(peer_id) if !db.is_blocked(&peer_id).await.unwrap_or(true) => {
+            db.get_peer(&peer_id).await.unwrap_or(None)
+        }
+        _ => None,
+    };
+    let peer = match peer {
+        Some(peer) => peer,
+        None => return Err((403, "Forbiden".to_string())),
+    };
+
+    if peer.pub_key != identity.0 {
+        log::info!("Update over TLS session of another key");
+        return Err((403, "Client certificate doesn't match peer".to_string()));
+    }
+
+    if !peer.enabled {
+        return Err((403, "Sharing is paused".to_string()));
+    }
+
+    // Keyed on the handshake, the peer id header is only a claim
+    let fingerprint =
+        get_fingerprint(&identity.0).map_err(|err| (500, err.to_string()))?;
+    if !rate_limiter.lock().await.check(&fingerprint) {
+        log::info!("Rate limited updates from {}", &peer.peer_id);
+        return Err((429, "Too many updates".to_string()));
+    }
+
+    if args.payload.plaintext_len() > get_config().max_clipboard_size {
+        return Err((413, "Clipboard too large".to_string()));
+    }
+    update(args, peer, nonce_cache, clipboard_state, db)
+        .await
+        .map_err(|err| (500, err.to_string()))
+}
+
+async fn update(
+    args: &UpdateArgs,
+    peer: peer::Model,
+    nonce_cache: web::Data<Arc<Mutex<NonceCache>>>,
+    clipboard_state: web::Data<Arc<Mutex<ClipboardState>>>,
+    db: &Database,
+) -> Result<Value, Error> {
     let mut response = "OK";
 
-    let db = Database::new().await?;
-    let peer_pub_key = db
-        .get_peer_pub_key(&args.remote_ip.as_ref().unwrap())
-        .await?
-        .unwrap();
     let result =
-        verify_message(&peer_pub_key, &args.signature, &args.clipboard).await;
+        verify_message(&peer.pub_key, &args.signature, &args.signed_content())
+            .await;
 
     if result.is_err() {
         response = "Failed to verify signature";
         log::info!("Failed attempt to update clipboard");
-    }
+    } else if peer.peer_id != args.sender_id {
+        response = "Unknown sender";
+        log::info!("Update signed by {} from wrong peer", &args.sender_id);
+    } else if !nonce_cache
+        .lock()
+        .await
+        .check_and_insert(&args.nonce, args.timestamp)
+    {
+        response = "Stale or replayed message";
+        log::info!("Rejected replayed update from {}", &args.sender_id);
+    } else {
+        let clipboard = decrypt_message(&args.payload).await?;
+
+        // Mark content as remote before the poller can see it
+        let mut clipboard_state = clipboard_state.lock().await;
+        if clipboard_state.record_applied(&args.message_id, &clipboard) {
+            set_clipboard(&clipboard).await?;
+            log::info!("Got new clipboard from {}", &args.sender_id);
+        } else {
+            log::info!("Clipboard {} already applied", &args.message_id);
+        }
+        drop(clipboard_state);
 
-    if result.is_ok() {
-        set_clipboard(&args.clipboard).await?;
-        log::info!("Got new clipboard from");
+        if let Some(remote_ip) = &args.remote_ip {
+            if db.retry_queued_update(&peer).await? {
+                wake_outbound_queue();
+            }
+            db.peer_seen(peer, remote_ip).await?;
+        }
     }
 
     Ok(json!(response))
@@ -44,3 +269,35 @@ async fn set_clipboard(content: &String) -> Result<(), Error> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::TokenBucket;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn bucket_runs_out_after_burst() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::full(3.0, now);
+        assert!((0..3).all(|_| bucket.take(now, 1.0, 3.0)));
+        assert!(!bucket.take(now, 1.0, 3.0));
+    }
+
+    #[test]
+    fn bucket_refills_with_time() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::full(1.0, now);
+        assert!(bucket.take(now, 2.0, 1.0));
+        assert!(!bucket.take(now + Duration::from_millis(100), 2.0, 1.0));
+        assert!(bucket.take(now + Duration::from_millis(600), 2.0, 1.0));
+    }
+
+    #[test]
+    fn bucket_caps_at_burst() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::full(2.0, now);
+        let later = now + Duration::from_secs(3600);
+        assert!((0..2).all(|_| bucket.take(later, 1.0, 2.0)));
+        assert!(!bucket.take(later, 1.0, 2.0));
+    }
+}
@@ -1,35 +1,190 @@
+use crate::utils::channel::{self, ChannelContext, PeerChannels};
+use crate::utils::config::get_config;
 use crate::utils::db::Database;
-use crate::utils::general::Response;
+use crate::utils::encryption::{get_fingerprint, EncryptedPayload};
+use crate::utils::general::{get_peer_id, is_authorized, Response};
+use crate::utils::tls::PeerIdentity;
 use crate::{share::controllers, utils::general::get_remote_ip};
-use actix_web::{post, web, HttpRequest, Responder};
-use serde::Deserialize;
+use actix_web::error::{InternalError, JsonPayloadError};
+use actix_web::{get, post, web, HttpRequest, Responder};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::Mutex;
 
-#[derive(Deserialize)]
+use super::controllers::{ClipboardState, NonceCache, RateLimiter};
+
+#[derive(Serialize, Deserialize)]
 pub struct UpdateArgs {
-    pub clipboard: String,
+    pub payload: EncryptedPayload,
+    pub message_id: String,
+    pub nonce: String,
+    pub timestamp: u64,
+    pub sender_id: String,
     pub signature: String,
+    #[serde(skip)]
     pub remote_ip: Option<String>,
 }
 
+impl UpdateArgs {
+    // Content covered by the sender's signature
+    pub fn signed_content(&self) -> String {
+        format!(
+            "{}.{}.{}.{}.{}",
+            self.sender_id,
+            self.message_id,
+            self.timestamp,
+            self.nonce,
+            self.payload.signed_content()
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SendArgs {
+    pub content: String,
+}
+
 #[post("/update")]
 async fn update(
     req: HttpRequest,
     data: web::Json<UpdateArgs>,
+    nonce_cache: web::Data<Arc<Mutex<NonceCache>>>,
+    clipboard_state: web::Data<Arc<Mutex<ClipboardState>>>,
+    rate_limiter: web::Data<Arc<Mutex<RateLimiter>>>,
+    db: web::Data<Database>,
 ) -> impl Responder {
-    // TODO replace it somehow
-    let db = Database::new().await.unwrap();
-    let peer_ip_list = db.get_peers_ip().await.unwrap_or(vec![]);
-    if !peer_ip_list.contains(&get_remote_ip(&req).await)
-        && get_remote_ip(&req).await != "127.0.0.1"
-    {
-        return Response::failure(403, "Forbiden".to_string());
-    }
+    // Peers authenticate with their key during the TLS handshake
+    let identity = match req.conn_data::<PeerIdentity>() {
+        Some(identity) => identity.clone(),
+        None => {
+            return Response::failure(
+                403,
+                "Client certificate required".to_string(),
+            )
+        }
+    };
 
     let mut args = data.into_inner();
     args.remote_ip = Some(get_remote_ip(&req).await);
-    let response = controllers::update(&args).await;
+    let response = controllers::receive_update(
+        &args,
+        get_peer_id(&req),
+        &identity,
+        nonce_cache,
+        clipboard_state,
+        rate_limiter,
+        &db,
+    )
+    .await;
+    match response {
+        Ok(data) => Response::success(data),
+        Err((code, msg)) => Response::failure(code, msg),
+    }
+}
+
+// Long-lived socket paired peers push updates over
+#[get("/channel")]
+async fn push_channel(
+    req: HttpRequest,
+    body: web::Payload,
+    nonce_cache: web::Data<Arc<Mutex<NonceCache>>>,
+    clipboard_state: web::Data<Arc<Mutex<ClipboardState>>>,
+    rate_limiter: web::Data<Arc<Mutex<RateLimiter>>>,
+    db: web::Data<Database>,
+    channels: web::Data<PeerChannels>,
+) -> impl Responder {
+    // Peers authenticate with their key during the TLS handshake
+    let identity = match req.conn_data::<PeerIdentity>() {
+        Some(identity) => identity.clone(),
+        None => {
+            return Response::failure(
+                403,
+                "Client certificate required".to_string(),
+            )
+        }
+    };
+
+    let paired = match get_fingerprint(&identity.0) {
+        Ok(peer_id) => {
+            !db.is_blocked(&peer_id).await.unwrap_or(true)
+                && matches!(db.get_peer(&peer_id).await, Ok(Some(_)))
+        }
+        Err(_) => false,
+    };
+    if !paired {
+        return Response::failure(403, "Forbiden".to_string());
+    }
+
+    let (response, session, stream) = match actix_ws::handle(&req, body) {
+        Ok(handshake) => handshake,
+        Err(e) => return Response::failure(400, e.to_string()),
+    };
+    let context = ChannelContext {
+        nonce_cache,
+        clipboard_state,
+        rate_limiter,
+        db: db.get_ref().clone(),
+        channels: channels.into_inner(),
+    };
+    let remote_ip = get_remote_ip(&req).await;
+    actix_web::rt::spawn(async move {
+        channel::serve(context, identity.0, remote_ip, session, stream)
+            .await
+            .unwrap_or_else(|err| log::error!("{}", err));
+    });
+    response
+}
+
+#[post("/withheld_clipboard/share")]
+async fn share_withheld(
+    req: HttpRequest,
+    clipboard_state: web::Data<Arc<Mutex<ClipboardState>>>,
+    db: web::Data<Database>,
+) -> impl Responder {
+    if !is_authorized(&req) {
+        return Response::failure(401, "Unauthorized".to_string());
+    }
+
+    let response = controllers::share_withheld(clipboard_state, &db).await;
     match response {
         Ok(data) => Response::success(data),
         Err(e) => Response::failure(500, e.to_string()),
     }
 }
+
+#[post("/send")]
+async fn send(
+    req: HttpRequest,
+    data: web::Json<SendArgs>,
+    db: web::Data<Database>,
+) -> impl Responder {
+    if !is_authorized(&req) {
+        return Response::failure(401, "Unauthorized".to_string());
+    }
+
+    let args = data.into_inner();
+    if args.content.len() > get_config().max_clipboard_size {
+        return Response::failure(413, "Content too large".to_string());
+    }
+    let response = controllers::send(&args, &db).await;
+    match response {
+        Ok(data) => Response::success(data),
+        Err(e) => Response::failure(500, e.to_string()),
+    }
+}
+
+// Oversized bodies get a 413 instead of actix's plain 400
+pub fn update_json_config() -> web::JsonConfig {
+    web::JsonConfig::default()
+        .limit(get_config().max_update_body_size())
+        .error_handler(|err, _req| {
+            let response = match err {
+                JsonPayloadError::Overflow { .. }
+                | JsonPayloadError::OverflowKnownLength { .. } => {
+                    Response::failure(413, err.to_string())
+                }
+                _ => Response::failure(400, err.to_string()),
+            };
+            InternalError::from_response(err, response).into()
+        })
+}
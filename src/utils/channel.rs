@@ -0,0 +1,456 @@
+use actix_web::web;
+use actix_ws::{Message as ServerMessage, MessageStream, Session};
+use futures_util::{
+    future, pin_mut, sink, stream, Sink, SinkExt, Stream, StreamExt,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot, Mutex, Notify};
+use tokio::time::{interval, timeout, Duration, Instant};
+use tokio_tungstenite::{
+    connect_async_tls_with_config,
+    tungstenite::{Error as WsError, Message},
+    Connector,
+};
+
+use super::communication::wake_outbound_queue;
+use super::encryption::{get_fingerprint, get_node_id, get_verify_key};
+use super::general::get_timestamp;
+use super::tls::client_config;
+use crate::entity::peer;
+use crate::share::controllers::{
+    receive_update, ClipboardState, NonceCache, RateLimiter,
+};
+use crate::share::routes::UpdateArgs;
+use crate::utils::{db::Database, error::Error, tls::PeerIdentity};
+
+// Seconds between presence messages, a peer silent for longer is gone
+const PRESENCE_INTERVAL: u64 = 30;
+const PRESENCE_TIMEOUT: u64 = 3 * PRESENCE_INTERVAL;
+// Seconds between attempts to open sockets to peers
+const DIAL_INTERVAL: u64 = 15;
+// An unanswered update is sent again over /update
+const ACK_TIMEOUT: u64 = 2;
+
+// Messages exchanged over the push channel
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChannelMessage {
+    Update(UpdateArgs),
+    Ack(Ack),
+    Presence { timestamp: u64 },
+}
+
+// Outcome of an update, with the status /update would have answered
+#[derive(Serialize, Deserialize)]
+pub struct Ack {
+    pub message_id: String,
+    pub status: u16,
+    pub data: Value,
+}
+
+// One open socket, whichever side dialed it
+struct Channel {
+    sender: mpsc::UnboundedSender<ChannelMessage>,
+    pending: Mutex<HashMap<String, oneshot::Sender<Ack>>>,
+    // Ends the loop serving the socket, presence would keep it alive
+    shutdown: Notify,
+    // Keys on both ends when it was opened, a rotation makes it stale
+    local_key: String,
+    peer_key: String,
+}
+
+// Long-lived sockets to paired peers, at most one per peer
+#[derive(Default)]
+pub struct PeerChannels {
+    channels: Mutex<HashMap<String, Arc<Channel>>>,
+    dialing: Mutex<HashSet<String>>,
+}
+
+impl PeerChannels {
+    async fn open(
+        &self,
+        peer_id: &str,
+        peer_key: &str,
+    ) -> Result<(Arc<Channel>, mpsc::UnboundedReceiver<ChannelMessage>), Error>
+    {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let channel = Arc::new(Channel {
+            sender,
+            pending: Mutex::new(HashMap::new()),
+            shutdown: Notify::new(),
+            local_key: get_verify_key()?,
+            peer_key: peer_key.to_owned(),
+        });
+
+        // A newer socket replaces the old one, which then winds down
+        if let Some(replaced) = self
+            .channels
+            .lock()
+            .await
+            .insert(peer_id.to_owned(), channel.clone())
+        {
+            replaced.shutdown.notify_one();
+        }
+        Ok((channel, receiver))
+    }
+
+    async fn close(&self, peer_id: &str, channel: &Arc<Channel>) {
+        let mut channels = self.channels.lock().await;
+        if channels
+            .get(peer_id)
+            .is_some_and(|open| Arc::ptr_eq(open, channel))
+        {
+            channels.remove(peer_id);
+        }
+        channel.shutdown.notify_one();
+    }
+
+    // Drops the socket to a peer that is unpaired or changed its key
+    pub async fn close_peer(&self, peer_id: &str) {
+        if let Some(channel) = self.channels.lock().await.remove(peer_id) {
+            channel.shutdown.notify_one();
+            log::info!("Closing push channel to {}", peer_id);
+        }
+    }
+
+    pub async fn is_open(&self, peer_id: &str) -> bool {
+        self.channels.lock().await.contains_key(peer_id)
+    }
+
+    // None if the update has to go over /update instead
+    pub async fn send_update(
+        &self,
+        peer: &peer::Model,
+        args: UpdateArgs,
+    ) -> Option<Ack> {
+        let channel = self.channels.lock().await.get(&peer.peer_id).cloned()?;
+        if channel.peer_key != peer.pub_key
+            || get_verify_key().ok().as_ref() != Some(&channel.local_key)
+        {
+            log::info!("Push channel to {} outlived a key", &peer.peer_id);
+            self.close(&peer.peer_id, &channel).await;
+            return None;
+        }
+
+        let message_id = args.message_id.clone();
+        let (ack_sender, ack_receiver) = oneshot::channel();
+        channel
+            .pending
+            .lock()
+            .await
+            .insert(message_id.clone(), ack_sender);
+        channel.sender.send(ChannelMessage::Update(args)).ok()?;
+        match timeout(Duration::from_secs(ACK_TIMEOUT), ack_receiver).await {
+            Ok(Ok(ack)) => Some(ack),
+            _ => {
+                channel.pending.lock().await.remove(&message_id);
+                None
+            }
+        }
+    }
+}
+
+// Everything an incoming update is checked and applied with
+#[derive(Clone)]
+pub struct ChannelContext {
+    pub nonce_cache: web::Data<Arc<Mutex<NonceCache>>>,
+    pub clipboard_state: web::Data<Arc<Mutex<ClipboardState>>>,
+    pub rate_limiter: web::Data<Arc<Mutex<RateLimiter>>>,
+    pub db: Database,
+    pub channels: Arc<PeerChannels>,
+}
+
+// The peer on the other end of a socket
+struct Link {
+    peer_id: String,
+    identity: PeerIdentity,
+    remote_ip: String,
+}
+
+async fn on_open(context: &ChannelContext, link: &Link) -> Result<(), Error> {
+    log::info!("Push channel to {} open", &link.peer_id);
+    let peer = match context.db.get_peer(&link.peer_id).await? {
+        Some(peer) => peer,
+        None => return Ok(()),
+    };
+
+    // Back online, no need to wait out the backoff
+    if context.db.retry_queued_update(&peer).await? {
+        wake_outbound_queue();
+    }
+    context.db.peer_seen(peer, &link.remote_ip).await
+}
+
+// Answer to send back, if any
+async fn on_message(
+    context: &ChannelContext,
+    link: &Link,
+    channel: &Channel,
+    text: &str,
+) -> Result<Option<ChannelMessage>, Error> {
+    match serde_json::from_str(text)? {
+        ChannelMessage::Update(mut args) => {
+            args.remote_ip = Some(link.remote_ip.clone());
+            let response = receive_update(
+                &args,
+                Some(link.peer_id.clone()),
+                &link.identity,
+                context.nonce_cache.clone(),
+                context.clipboard_state.clone(),
+                context.rate_limiter.clone(),
+                &context.db,
+            )
+            .await;
+            let (status, data) = match response {
+                Ok(data) => (200, data),
+                Err((status, msg)) => (status, Value::from(msg)),
+            };
+            Ok(Some(ChannelMessage::Ack(Ack {
+                message_id: args.message_id,
+                status,
+                data,
+            })))
+        }
+        ChannelMessage::Ack(ack) => {
+            if let Some(sender) =
+                channel.pending.lock().await.remove(&ack.message_id)
+            {
+                sender.send(ack).ok();
+            }
+            Ok(None)
+        }
+        ChannelMessage::Presence { .. } => {
+            if let Some(peer) = context.db.get_peer(&link.peer_id).await? {
+                context.db.peer_seen(peer, &link.remote_ip).await?;
+            }
+            Ok(None)
+        }
+    }
+}
+
+async fn answer(
+    context: &ChannelContext,
+    link: &Link,
+    channel: &Channel,
+    text: &str,
+) -> Option<ChannelMessage> {
+    on_message(context, link, channel, text)
+        .await
+        .unwrap_or_else(|err| {
+            log::error!("Bad message from {}: {}", &link.peer_id, err);
+            None
+        })
+}
+
+// Presence to send, None once the peer has been silent for too long
+fn presence(last_heard: Instant) -> Option<ChannelMessage> {
+    if last_heard.elapsed() > Duration::from_secs(PRESENCE_TIMEOUT) {
+        return None;
+    }
+    Some(ChannelMessage::Presence {
+        timestamp: get_timestamp(),
+    })
+}
+
+// Serves one socket until either side closes it or the peer goes quiet.
+// Only text frames reach this, the transports deal with the rest.
+async fn run(
+    context: &ChannelContext,
+    link: &Link,
+    stream: impl Stream<Item = String>,
+    sink: impl Sink<String>,
+) -> Result<(), Error> {
+    pin_mut!(stream, sink);
+    let (channel, mut outgoing) = context
+        .channels
+        .open(&link.peer_id, &link.identity.0)
+        .await?;
+    on_open(context, link)
+        .await
+        .unwrap_or_else(|err| log::error!("{}", err));
+
+    let mut ticker = interval(Duration::from_secs(PRESENCE_INTERVAL));
+    let mut last_heard = Instant::now();
+    loop {
+        let message = tokio::select! {
+            message = outgoing.recv() => match message {
+                Some(message) => message,
+                None => break,
+            },
+            _ = channel.shutdown.notified() => break,
+            text = stream.next() => {
+                let text = match text {
+                    Some(text) => text,
+                    None => break,
+                };
+                last_heard = Instant::now();
+                match answer(context, link, &channel, &text).await {
+                    Some(answer) => answer,
+                    None => continue,
+                }
+            },
+            _ = ticker.tick() => match presence(last_heard) {
+                Some(presence) => presence,
+                None => break,
+            },
+        };
+        if sink.send(serde_json::to_string(&message)?).await.is_err() {
+            break;
+        }
+    }
+
+    context.channels.close(&link.peer_id, &channel).await;
+    sink.close().await.ok();
+    log::info!("Push channel to {} closed", &link.peer_id);
+    Ok(())
+}
+
+// Socket a peer opened to the node, after /channel checked its certificate
+pub async fn serve(
+    context: ChannelContext,
+    pub_key: String,
+    remote_ip: String,
+    session: Session,
+    stream: MessageStream,
+) -> Result<(), Error> {
+    let link = Link {
+        peer_id: get_fingerprint(&pub_key)?,
+        identity: PeerIdentity(pub_key),
+        remote_ip,
+    };
+
+    // Pings are answered here, the client side library does it by itself
+    let texts = stream::unfold(
+        (stream, session.clone()),
+        |(mut stream, mut session)| async move {
+            loop {
+                match stream.recv().await {
+                    Some(Ok(ServerMessage::Text(text))) => {
+                        return Some((text.to_string(), (stream, session)))
+                    }
+                    Some(Ok(ServerMessage::Ping(bytes))) => {
+                        session.pong(&bytes).await.ok();
+                    }
+                    Some(Ok(ServerMessage::Close(_))) | Some(Err(_)) | None => {
+                        return None
+                    }
+                    Some(Ok(_)) => {}
+                }
+            }
+        },
+    );
+    let replies =
+        sink::unfold(session.clone(), |mut session, text: String| async move {
+            session.text(text).await.map(|_| session)
+        });
+    run(&context, &link, texts, replies).await?;
+    session.close(None).await.ok();
+    Ok(())
+}
+
+// Keeps a socket open to each paired peer. Only the node with the lower
+// id dials, so each pair shares one socket.
+pub async fn start_channels(context: ChannelContext) {
+    let mut ticker = interval(Duration::from_secs(DIAL_INTERVAL));
+    loop {
+        ticker.tick().await;
+        let node_id = match get_node_id() {
+            Ok(node_id) => node_id,
+            Err(err) => {
+                log::error!("{}", err);
+                continue;
+            }
+        };
+        let peers = match context.db.get_peers().await {
+            Ok(peers) => peers,
+            Err(err) => {
+                log::error!("{}", err);
+                continue;
+            }
+        };
+
+        for peer in peers {
+            if !peer.enabled
+                || peer.peer_id <= node_id
+                || context.channels.is_open(&peer.peer_id).await
+                || !context
+                    .channels
+                    .dialing
+                    .lock()
+                    .await
+                    .insert(peer.peer_id.clone())
+            {
+                continue;
+            }
+
+            let context = context.clone();
+            tokio::spawn(async move {
+                dial(&context, &peer)
+                    .await
+                    .unwrap_or_else(|err| log::error!("{}", err));
+                context.channels.dialing.lock().await.remove(&peer.peer_id);
+            });
+        }
+    }
+}
+
+async fn dial(
+    context: &ChannelContext,
+    peer: &peer::Model,
+) -> Result<(), Error> {
+    // The handshake only succeeds with the peer holding its paired key
+    let connector =
+        Connector::Rustls(Arc::new(client_config(Some(&peer.pub_key))?));
+    for address in context.db.get_peer_addresses(peer).await? {
+        let url = format!("wss://{}:{}/channel", &address.ip, address.port);
+        let result = connect_async_tls_with_config(
+            &url,
+            None,
+            false,
+            Some(connector.clone()),
+        )
+        .await;
+        let socket = match result {
+            Ok((socket, _)) => socket,
+            Err(err) => {
+                log::info!(
+                    "No push channel to {} at {}: {}",
+                    &peer.peer_id,
+                    &address.ip,
+                    err
+                );
+                continue;
+            }
+        };
+
+        let link = Link {
+            peer_id: peer.peer_id.clone(),
+            identity: PeerIdentity(peer.pub_key.clone()),
+            remote_ip: address.ip.clone(),
+        };
+        context.db.peer_reached(address).await?;
+
+        let (sink, stream) = socket.split();
+        let texts = stream::unfold(stream, |mut stream| async move {
+            loop {
+                match stream.next().await {
+                    Some(Ok(Message::Text(text))) => {
+                        return Some((text, stream))
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                        return None
+                    }
+                    Some(Ok(_)) => {}
+                }
+            }
+        });
+        let replies = sink.with(|text: String| {
+            future::ready(Ok::<_, WsError>(Message::Text(text)))
+        });
+        return run(context, &link, texts, replies).await;
+    }
+    Ok(())
+}
@@ -1,154 +1,544 @@
+use super::channel::{Ack, PeerChannels};
 use super::controllers::SOCKET;
 
-use super::encryption::get_digest;
-use super::encryption::sign_message;
-use crate::connect::controllers::echo_helpers::get_local_ip;
-use crate::utils::{db::Database, error::Error};
-use reqwest::Client;
-use serde_json::json;
-use std::{
-    net::{Ipv4Addr, SocketAddrV4},
-    str::FromStr,
-    sync::Arc,
+use super::config::get_config;
+use super::encryption::{
+    encrypt_message, generate_nonce, get_fingerprint, get_node_id,
+    get_verify_key, sign_message, verify_message,
 };
-use tokio::{sync::Mutex, time::Duration};
+use super::general::{get_timestamp, is_fresh};
+use crate::entity::{peer, peer_address};
+use crate::share::controllers::NonceCache;
+use crate::share::routes::UpdateArgs;
+use crate::utils::{db::Database, error::Error, tls::PeerClient};
+use lazy_static::lazy_static;
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::{net::SocketAddrV4, str::FromStr, sync::Arc};
+use tokio::{
+    sync::{Mutex, Notify},
+    time::Duration,
+};
+
+// Bumped whenever nodes stop understanding each other
+pub const PROTOCOL_VERSION: u32 = 1;
+// Delivery is retried, so a peer that hangs shouldn't hold up the queue
+const DELIVERY_TIMEOUT: u64 = 2;
+// Retry delays of queued updates, in seconds
+const RETRY_BASE: u64 = 5;
+const RETRY_MAX: u64 = 60 * 60;
+
+// Clipboard this old is dropped rather than pasted on a peer
+const MAX_QUEUE_AGE: u64 = 24 * 60 * 60;
+
+lazy_static! {
+    static ref OUTBOUND_QUEUE: Notify = Notify::new();
+}
+
+pub const BEACON_PROBE: &str = "probe";
+pub const BEACON_ANNOUNCE: &str = "announce";
+
+// Multicast message nodes find each other with
+#[derive(Serialize, Deserialize)]
+pub struct Beacon {
+    pub kind: String,
+    pub node_id: String,
+    pub pub_key: String,
+    pub version: u32,
+    pub port: u16,
+    pub nonce: String,
+    pub timestamp: u64,
+    pub signature: String,
+}
+
+impl Beacon {
+    pub async fn new(kind: &str) -> Result<Self, Error> {
+        let pub_key = get_verify_key()?;
+        let mut beacon = Self {
+            kind: kind.to_owned(),
+            node_id: get_fingerprint(&pub_key)?,
+            pub_key,
+            version: PROTOCOL_VERSION,
+            port: get_config().node_port,
+            nonce: generate_nonce()?,
+            timestamp: get_timestamp(),
+            signature: String::new(),
+        };
+        beacon.signature = sign_message(&beacon.signed_content()).await?;
+        Ok(beacon)
+    }
+
+    // Content covered by the sender's signature
+    pub fn signed_content(&self) -> String {
+        format!(
+            "{}.{}.{}.{}.{}.{}.{}",
+            self.kind,
+            self.node_id,
+            self.pub_key,
+            self.version,
+            self.port,
+            self.nonce,
+            self.timestamp
+        )
+    }
+
+    pub async fn verify(&self) -> bool {
+        self.version == PROTOCOL_VERSION
+            && is_fresh(self.timestamp)
+            && get_fingerprint(&self.pub_key).ok().as_ref()
+                == Some(&self.node_id)
+            && verify_message(
+                &self.pub_key,
+                &self.signature,
+                &self.signed_content(),
+            )
+            .await
+            .is_ok()
+    }
+}
+
+impl std::fmt::Display for Beacon {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", serde_json::to_string(self).unwrap_or_default())
+    }
+}
+
+pub async fn update_peers(
+    db: &Database,
+    clipboard: String,
+) -> Result<(), Error> {
+    if clipboard.len() > get_config().max_clipboard_size {
+        log::info!("Clipboard of {} bytes too large to share", clipboard.len());
+        return Ok(());
+    }
 
-pub async fn update_peers(clipboard: String) -> Result<(), Error> {
     // Define data
-    let db = Database::new().await?;
-    let peers_ip = db.get_peers_ip().await?;
-    let mut handles = Vec::new();
-    let signature = sign_message(&clipboard).await?;
+    let peers = db.get_peers().await?;
+    let message_id = generate_nonce()?;
 
     // Iterate through all peers
-    for peer in peers_ip {
-        let signature = signature.clone();
-        let clipboard = clipboard.clone();
-        let handle = tokio::spawn(async move {
-            let url = format!("http://{}:9898/update", &peer);
-            let body = json!({"clipboard": clipboard, "signature": signature});
-            let client = Client::builder()
-                .timeout(Duration::from_secs(2))
-                .build()
-                .unwrap();
-            let response = client
-                .post(&url)
-                .header(reqwest::header::CONTENT_TYPE, "application/json")
-                .body(body.to_string())
-                .send()
-                .await;
-            let response = response.ok();
-            match response {
-                Some(data) => {
-                    let res = data.text().await.unwrap();
-                    let tost =
-                        serde_json::Value::from_str(&res.clone()).unwrap();
-                    let dat = tost.get("data").unwrap().clone();
-                    if dat.to_string() == "\"OK\"".to_string() {
-                        log::info!(
-                            "{}",
-                            format!(
-                                "Clipboard shared with {} successfully",
-                                &peer.to_owned(),
-                            )
-                        );
-                    } else {
-                        log::info!(
-                            "{}",
-                            format!(
-                                "Failed to share clipboard with peer {}: {}",
-                                &peer.to_owned(),
-                                res
-                            )
-                        );
-                    }
-                }
-                None => log::info!(
-                    "{}",
-                    format!(
-                        "Failed to share clipboard with peer {}, no response",
-                        &peer.to_owned(),
-                    )
-                ),
+    for peer in peers {
+        // Sharing with this peer is paused
+        if !peer.enabled {
+            continue;
+        }
+
+        let agreement_key = match &peer.agreement_key {
+            Some(agreement_key) => agreement_key,
+            None => {
+                log::info!(
+                    "Skipping peer {}, no agreement key, pair it again",
+                    &peer.ip
+                );
+                continue;
+            }
+        };
+
+        // Queued content stays encrypted to the peer at rest
+        let payload = match encrypt_message(agreement_key, &clipboard).await {
+            Ok(payload) => payload,
+            Err(err) => {
+                log::error!(
+                    "Skipping peer {}, can't encrypt to it: {}",
+                    &peer.peer_id,
+                    err
+                );
+                continue;
             }
+        };
+        db.queue_update(&peer, &message_id, &serde_json::to_string(&payload)?)
+            .await?;
+    }
+
+    wake_outbound_queue();
+    Ok(())
+}
+
+pub fn wake_outbound_queue() {
+    OUTBOUND_QUEUE.notify_one();
+}
+
+// Delivers queued updates, retrying with exponential backoff
+pub async fn start_outbound_queue(
+    db: Database,
+    client: Arc<PeerClient>,
+    channels: Arc<PeerChannels>,
+) {
+    loop {
+        let wait = deliver_queued_updates(&db, &client, &channels)
+            .await
+            .unwrap_or_else(|err| {
+                log::error!("{}", err);
+                RETRY_BASE
+            });
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(wait)) => {}
+            _ = OUTBOUND_QUEUE.notified() => {}
+        }
+    }
+}
+
+enum Delivery {
+    // With the address it went to, unless it was pushed over the channel
+    Applied(Option<peer_address::Model>),
+    Refused,
+    Retry,
+}
+
+// Returns the seconds until the next queued update is due
+async fn deliver_queued_updates(
+    db: &Database,
+    client: &PeerClient,
+    channels: &Arc<PeerChannels>,
+) -> Result<u64, Error> {
+    let sender_id = get_node_id()?;
+    let now = get_timestamp() as i64;
+    let mut wait = RETRY_MAX;
+    let mut handles = Vec::new();
+
+    for update in db.get_queued_updates().await? {
+        if now - update.created_at > MAX_QUEUE_AGE as i64 {
+            log::info!("Dropped stale queued clipboard {}", &update.message_id);
+            db.remove_queued_update(&update).await?;
+            continue;
+        }
+        if update.next_attempt > now {
+            wait = wait.min((update.next_attempt - now) as u64);
+            continue;
+        }
+        let peer = match db.get_peer_by_id(update.peer_id).await? {
+            Some(peer) if peer.enabled => peer,
+            _ => {
+                db.remove_queued_update(&update).await?;
+                continue;
+            }
+        };
+
+        // Signed anew on each attempt, receivers reject stale messages
+        let mut args = UpdateArgs {
+            payload: serde_json::from_str(&update.payload)?,
+            message_id: update.message_id.clone(),
+            nonce: generate_nonce()?,
+            timestamp: get_timestamp(),
+            sender_id: sender_id.clone(),
+            signature: String::new(),
+            remote_ip: None,
+        };
+        args.signature = sign_message(&args.signed_content()).await?;
+        let body = serde_json::to_string(&args)?;
+        let client = client.get(&peer.pub_key)?;
+        let addresses = db.get_peer_addresses(&peer).await?;
+        let channels = channels.clone();
+        let handle = tokio::spawn(async move {
+            // Pushed over the peer's socket when open, /update otherwise
+            let delivery = match channels.send_update(&peer, args).await {
+                Some(ack) => acknowledged(&peer.peer_id, ack),
+                None => deliver(&client, &peer, addresses, &body).await,
+            };
+            (update, delivery)
         });
         handles.push(handle);
     }
 
     for handle in handles {
-        handle.await.unwrap_or_else(|err| {
-            log::error!("{}", format!("tokio error: {}", err))
-        });
+        match handle.await {
+            Ok((update, Delivery::Applied(address))) => {
+                // Remember which address worked for next time
+                if let Some(address) = address {
+                    db.peer_reached(address).await?;
+                }
+                db.remove_queued_update(&update).await?;
+            }
+            Ok((update, Delivery::Refused)) => {
+                db.remove_queued_update(&update).await?;
+            }
+            Ok((update, Delivery::Retry)) => {
+                let delay = RETRY_BASE
+                    .saturating_mul(1 << update.attempts.min(16))
+                    .min(RETRY_MAX);
+                db.postpone_queued_update(&update, delay).await?;
+                wait = wait.min(delay);
+            }
+            Err(err) => log::error!("{}", format!("tokio error: {}", err)),
+        }
     }
 
-    Ok(())
+    Ok(wait)
 }
 
-pub async fn start_broadcasting(potential_peer_list: Arc<Mutex<Vec<String>>>) {
-    let multicast_addr: Ipv4Addr = "239.0.0.1".parse().unwrap();
-    let port: u16 = 23235;
+async fn deliver(
+    client: &Client,
+    peer: &peer::Model,
+    addresses: Vec<peer_address::Model>,
+    body: &str,
+) -> Delivery {
+    let peer_id = &peer.peer_id;
+    let count = addresses.len();
+    for address in addresses {
+        let url = format!("https://{}:{}/update", &address.ip, address.port);
+        let response = client
+            .post(&url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_owned())
+            .timeout(Duration::from_secs(DELIVERY_TIMEOUT))
+            .send()
+            .await;
+        let response = match response {
+            Ok(response) => response,
+            Err(err) => {
+                log::info!(
+                    "Peer {} not reachable at {}: {}",
+                    peer_id,
+                    &address.ip,
+                    err
+                );
+                continue;
+            }
+        };
+
+        // Peer answered, other addresses would get the same
+        let status = response.status();
+        let response = response.text().await.unwrap_or_default();
+        let data = serde_json::Value::from_str(&response)
+            .ok()
+            .and_then(|response| response.get("data").cloned());
+        if data == Some(serde_json::Value::from("OK")) {
+            log::info!("Clipboard shared with {} at {}", peer_id, &address.ip);
+            return Delivery::Applied(Some(address));
+        }
+        log::info!(
+            "Failed to share clipboard with peer {}: {}",
+            peer_id,
+            response
+        );
+        return failed(status);
+    }
 
-    //tokio::spawn(multicast_client(multicast_addr, port));
-    tokio::spawn(multicast_server(potential_peer_list));
+    log::info!(
+        "Peer {} unreachable at {} addresses, will retry",
+        peer_id,
+        count
+    );
+    Delivery::Retry
+}
+
+fn acknowledged(peer_id: &str, ack: Ack) -> Delivery {
+    if ack.status == StatusCode::OK && ack.data == "OK" {
+        log::info!("Clipboard pushed to {}", peer_id);
+        return Delivery::Applied(None);
+    }
+    log::info!(
+        "Failed to share clipboard with peer {}: {}",
+        peer_id,
+        ack.data
+    );
+    failed(
+        StatusCode::from_u16(ack.status)
+            .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+    )
 }
-// Used to send ping to other peers
-pub async fn multicast_client(multicast_addr: Ipv4Addr, port: u16) {
+
+// Busy or failing peers get the update again later, refusals are final
+fn failed(status: StatusCode) -> Delivery {
+    if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+        return Delivery::Retry;
+    }
+    Delivery::Refused
+}
+
+pub async fn start_broadcasting(
+    potential_peer_list: Arc<Mutex<Vec<String>>>,
+    db: Database,
+    client: Arc<PeerClient>,
+) {
+    if get_config().announce_interval > 0 {
+        tokio::spawn(multicast_client());
+    }
+    tokio::spawn(multicast_server(potential_peer_list, db, client));
+}
+
+// Lets paired peers notice when our address changes
+pub async fn multicast_client() {
     let socket = SOCKET.get().await;
+    let config = get_config();
+    let group =
+        SocketAddrV4::new(config.multicast_group, config.multicast_port);
     loop {
-        // Send ping message
-        let ping_msg = "Ping";
-        socket
-            .send_to(
-                ping_msg.as_bytes(),
-                SocketAddrV4::new(multicast_addr, port),
-            )
-            .await
-            .expect("BAN");
+        match Beacon::new(BEACON_ANNOUNCE).await {
+            Ok(beacon) => {
+                socket
+                    .send_to(beacon.to_string().as_bytes(), group)
+                    .await
+                    .unwrap_or_else(|err| {
+                        log::error!("Failed to announce: {}", err);
+                        0
+                    });
+            }
+            Err(err) => log::error!("Failed to build beacon: {}", err),
+        }
 
-        // Wait before sending the next ping
-        tokio::time::sleep(Duration::from_secs(10)).await;
+        // Wait before announcing again
+        tokio::time::sleep(Duration::from_secs(config.announce_interval)).await;
     }
 }
 
 // Used to receive that ping
-pub async fn multicast_server(potential_peer_list: Arc<Mutex<Vec<String>>>) {
+pub async fn multicast_server(
+    potential_peer_list: Arc<Mutex<Vec<String>>>,
+    db: Database,
+    client: Arc<PeerClient>,
+) {
     let mut buf = [0u8; 4096];
     let socket = SOCKET.get().await;
+    let mut beacon_nonces = NonceCache::new();
 
     loop {
         if let Ok((size, addr)) = socket.recv_from(&mut buf).await {
-            // Skip requests from localhost
-            if addr.ip().to_string() == get_local_ip().await {
+            // Anything that isn't a valid beacon from another node is noise
+            let beacon = match serde_json::from_slice::<Beacon>(&buf[..size]) {
+                Ok(beacon) => beacon,
+                Err(_) => continue,
+            };
+            if !beacon.verify().await {
+                log::info!("Dropped invalid beacon from {}", addr.ip());
+                continue;
+            }
+            if Some(&beacon.node_id) == get_node_id().ok().as_ref() {
+                continue;
+            }
+            if !beacon_nonces.check_and_insert(&beacon.nonce, beacon.timestamp)
+            {
+                log::info!("Dropped replayed beacon from {}", addr.ip());
+                continue;
+            }
+            if db.is_blocked(&beacon.node_id).await.unwrap_or(false) {
+                log::info!("Ignored beacon from blocked {}", &beacon.node_id);
                 continue;
             }
 
-            let response = String::from_utf8_lossy(&buf[..size]);
+            // Paired peer may have a new address since we last heard of it
+            refresh_peer_address(&db, &client, &beacon, &addr.ip().to_string())
+                .await
+                .unwrap_or_else(|err| log::error!("{}", err));
 
-            if response == get_digest("resk").await.as_str() {
-                socket
-                    .send_to(get_digest("yes").await.as_bytes(), addr)
-                    .await
-                    .unwrap_or_else(|err| {
-                        log::error!("Error pinging back: {}", err);
-                        0 as usize
-                    });
-                log::info!("Ping sent back to: {}", addr.ip());
-            }
-            if response == get_digest("yes").await.as_str() {
-                let mut vec = potential_peer_list.lock().await;
-                if !vec.contains(&addr.ip().to_string()) {
-                    vec.push(addr.ip().to_string());
-                    log::info!("Discovered host: {}", addr.ip());
+            match beacon.kind.as_str() {
+                BEACON_PROBE => {
+                    let reply = match Beacon::new(BEACON_ANNOUNCE).await {
+                        Ok(reply) => reply,
+                        Err(err) => {
+                            log::error!("Failed to build beacon: {}", err);
+                            continue;
+                        }
+                    };
+                    // Every instance on the sender's host shares the port,
+                    // only the group reaches all of them
+                    let config = get_config();
+                    let group = SocketAddrV4::new(
+                        config.multicast_group,
+                        config.multicast_port,
+                    );
+                    socket
+                        .send_to(reply.to_string().as_bytes(), group)
+                        .await
+                        .unwrap_or_else(|err| {
+                            log::error!("Error pinging back: {}", err);
+                            0
+                        });
+                    log::info!("Ping sent back to: {}", addr.ip());
                 }
+                BEACON_ANNOUNCE => {
+                    let host = format!("{}:{}", addr.ip(), beacon.port);
+                    let mut vec = potential_peer_list.lock().await;
+                    if !vec.contains(&host) {
+                        vec.push(host);
+                        log::info!(
+                            "Discovered host: {} ({})",
+                            addr.ip(),
+                            &beacon.node_id
+                        );
+                    }
+                }
+                _ => {}
             }
         }
     }
 }
 
+async fn refresh_peer_address(
+    db: &Database,
+    client: &PeerClient,
+    beacon: &Beacon,
+    ip: &str,
+) -> Result<(), Error> {
+    let peer = match db.get_peer(&beacon.node_id).await? {
+        Some(peer) => peer,
+        None => return Ok(()),
+    };
+
+    // The beacon only names an address, anyone on the network can send
+    // it from elsewhere, so it's used once the peer answers there
+    let address = db.add_peer_address(&peer, ip, beacon.port).await?;
+    let (db, client) = (db.clone(), client.get(&peer.pub_key)?);
+    tokio::spawn(async move {
+        let result =
+            reach_peer(&client, &address.ip, address.port as u16).await;
+        if let Err(err) = result {
+            log::info!(
+                "Peer {} not reachable at {}: {}",
+                &peer.peer_id,
+                &address.ip,
+                err
+            );
+            return;
+        }
+        if peer.ip != address.ip || peer.port != address.port {
+            log::info!(
+                "Peer {} moved from {}:{} to {}:{}",
+                &peer.peer_id,
+                &peer.ip,
+                peer.port,
+                &address.ip,
+                address.port
+            );
+        }
+        db.peer_reached(address)
+            .await
+            .unwrap_or_else(|err| log::error!("{}", err));
+
+        // Back online, no need to wait out the backoff
+        if db.retry_queued_update(&peer).await.unwrap_or(false) {
+            wake_outbound_queue();
+        }
+    });
+    Ok(())
+}
+
+// Round trip on the peer's pinned key, without changing anything
+pub async fn reach_peer(
+    client: &Client,
+    ip: &str,
+    port: u16,
+) -> Result<(), Error> {
+    let url = format!(
+        "https://{}:{}/echo?challenge={}",
+        ip,
+        port,
+        generate_nonce()?
+    );
+    let response = client.get(&url).send().await?;
+    if !response.status().is_success() {
+        return Err(Error::Generic(
+            format!("Peer answered {}", response.status()).into(),
+        ));
+    }
+    Ok(())
+}
+
 pub async fn send_multicast_msg(msg: &str) -> Result<(), Error> {
     let socket = SOCKET.get().await;
-    let multicast_addr: Ipv4Addr = "239.0.0.1".parse().unwrap();
-    let port: u16 = 23235;
+    let config = get_config();
+    let multicast_addr = config.multicast_group;
+    let port = config.multicast_port;
 
     let dest = SocketAddrV4::new(multicast_addr, port);
 
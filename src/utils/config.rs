@@ -0,0 +1,135 @@
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use std::env;
+use std::fs;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+use super::error::Error;
+use super::general::get_config_path;
+
+lazy_static! {
+    static ref CONFIG: RwLock<Config> = RwLock::new(Config::default());
+}
+
+// Settings from config.toml in the data dir, each one can be overridden with
+// the RESK_<NAME> environment variable, e.g. RESK_NODE_PORT
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind_address: Ipv4Addr,
+    pub node_port: u16,
+    pub control_port: u16,
+    pub multicast_group: Ipv4Addr,
+    pub multicast_port: u16,
+    pub max_clipboard_size: usize,
+    pub update_rate: f64,
+    pub update_burst: f64,
+    pub announce_interval: u64,
+    pub db_path: Option<String>,
+    pub log_file: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind_address: Ipv4Addr::UNSPECIFIED,
+            node_port: 9898,
+            control_port: 9899,
+            multicast_group: Ipv4Addr::new(239, 0, 0, 1),
+            multicast_port: 23235,
+            max_clipboard_size: 1024 * 1024,
+            update_rate: 1.0,
+            update_burst: 10.0,
+            announce_interval: 60,
+            db_path: None,
+            log_file: None,
+        }
+    }
+}
+
+impl Config {
+    fn from_file(path: &str) -> Result<Self, Error> {
+        if !Path::new(path).exists() {
+            return Ok(Self::default());
+        }
+        Ok(toml::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    fn apply_env(&mut self) -> Result<(), Error> {
+        override_from_env("RESK_BIND_ADDRESS", &mut self.bind_address)?;
+        override_from_env("RESK_NODE_PORT", &mut self.node_port)?;
+        override_from_env("RESK_CONTROL_PORT", &mut self.control_port)?;
+        override_from_env("RESK_MULTICAST_GROUP", &mut self.multicast_group)?;
+        override_from_env("RESK_MULTICAST_PORT", &mut self.multicast_port)?;
+        override_from_env(
+            "RESK_MAX_CLIPBOARD_SIZE",
+            &mut self.max_clipboard_size,
+        )?;
+        override_from_env("RESK_UPDATE_RATE", &mut self.update_rate)?;
+        override_from_env("RESK_UPDATE_BURST", &mut self.update_burst)?;
+        override_from_env(
+            "RESK_ANNOUNCE_INTERVAL",
+            &mut self.announce_interval,
+        )?;
+        if let Ok(db_path) = env::var("RESK_DB_PATH") {
+            self.db_path = Some(db_path);
+        }
+        if let Ok(log_file) = env::var("RESK_LOG_FILE") {
+            self.log_file = Some(log_file);
+        }
+        Ok(())
+    }
+
+    // Base64 ciphertext plus the rest of the update message
+    pub fn max_update_body_size(&self) -> usize {
+        self.max_clipboard_size / 3 * 4 + 4096
+    }
+}
+
+fn override_from_env<T: FromStr>(
+    name: &str,
+    value: &mut T,
+) -> Result<(), Error> {
+    if let Ok(raw) = env::var(name) {
+        *value = raw
+            .parse()
+            .map_err(|_| Error::Generic(format!("Invalid {}", name).into()))?;
+    }
+    Ok(())
+}
+
+pub fn load_config() -> Result<(), Error> {
+    let mut config = Config::from_file(&get_config_path())?;
+    config.apply_env()?;
+    *CONFIG.write().unwrap() = config;
+    Ok(())
+}
+
+pub fn get_config() -> Config {
+    CONFIG.read().unwrap().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn env_overrides_file() {
+        let path =
+            env::temp_dir().join(format!("resk-{}.toml", std::process::id()));
+        fs::write(&path, "node_port = 1111\ncontrol_port = 2222\n").unwrap();
+        env::set_var("RESK_NODE_PORT", "3333");
+
+        let mut config = Config::from_file(path.to_str().unwrap()).unwrap();
+        config.apply_env().unwrap();
+        env::remove_var("RESK_NODE_PORT");
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.node_port, 3333);
+        assert_eq!(config.control_port, 2222);
+        assert_eq!(config.multicast_port, Config::default().multicast_port);
+    }
+}
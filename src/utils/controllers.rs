@@ -1,13 +1,27 @@
-use crate::share::routes::update;
+use crate::share::{
+    controllers::{ClipboardState, NonceCache, RateLimiter},
+    routes::{push_channel, send, share_withheld, update, update_json_config},
+};
 use crate::utils::{
+    channel::{start_channels, ChannelContext, PeerChannels},
+    config::{get_config, load_config},
     db::Database,
+    encryption::unlock_sign_key,
     error::Error,
-    general::{check_keys, get_log_file_path},
+    general::{check_keys, get_data_dir, get_log_file_path},
+    secrets::detect_secret,
+    tls::{on_connect, server_config, PeerClient},
 };
 use crate::{
     connect::{
         controllers::echo_helpers::get_local_ip,
-        routes::{connect_peer, echo, scan},
+        routes::{
+            accept_pairing_request, blocked_peers, confirm_pairing_request,
+            connect_peer, delete_peer, echo, get_peer, handover, keys, pair,
+            pair_accept, pair_reject, pair_reveal, pairing_requests, peers,
+            reject_pairing_request, revoke_peer, rotate_keys, scan, status,
+            unblock_peer, unpair_peer, unpaired, update_peer,
+        },
     },
     utils::general::get_db_path,
 };
@@ -21,6 +35,7 @@ use log4rs::{
     config::{Appender, Config, Root},
     encode::pattern::PatternEncoder,
 };
+use socket2::{Domain, Protocol, Socket, Type};
 use std::{
     net::{Ipv4Addr, SocketAddrV4},
     path::Path,
@@ -29,7 +44,12 @@ use std::{
 use tokio::fs::OpenOptions;
 use tokio::{net::UdpSocket as TokioUdpSocket, sync::Mutex, time::Duration};
 
-use super::communication::{start_broadcasting, update_peers};
+use super::communication::{
+    start_broadcasting, start_outbound_queue, update_peers,
+};
+
+// Peers reuse their connection to the node between updates
+const NODE_KEEP_ALIVE: u64 = 75;
 
 lazy_static! {
     pub static ref DATABASE: AsyncOnce<Database> =
@@ -39,43 +59,134 @@ lazy_static! {
 }
 
 pub async fn run() -> Result<(), Error> {
+    // Settings decide where everything else lives
+    load_config()?;
+    let config = get_config();
+
     // Define data
     let potential_peer_list: Arc<Mutex<Vec<String>>> =
         Arc::new(Mutex::new(vec![]));
+    let nonce_cache: Arc<Mutex<NonceCache>> =
+        Arc::new(Mutex::new(NonceCache::new()));
+    let clipboard_state: Arc<Mutex<ClipboardState>> =
+        Arc::new(Mutex::new(ClipboardState::new()));
+    let rate_limiter: Arc<Mutex<RateLimiter>> =
+        Arc::new(Mutex::new(RateLimiter::new()));
 
     // Check if files are inplace and init logger
     pre_run().await?;
 
+    // One pool and one keep-alive client, shared by every handler and task
+    let db = DATABASE.get().await.clone();
+    let peer_client = Arc::new(PeerClient::default());
+    let channels = Arc::new(PeerChannels::default());
+
     // polling to trigger if need to update clipboard of peers
-    tokio::spawn(start_pooling_clipboard());
+    tokio::spawn(start_pooling_clipboard(clipboard_state.clone(), db.clone()));
+
+    // delivery of queued clipboard updates, retried while peers are away
+    tokio::spawn(start_outbound_queue(
+        db.clone(),
+        peer_client.clone(),
+        channels.clone(),
+    ));
+
+    // sockets to paired peers, updates are pushed over them when open
+    tokio::spawn(start_channels(ChannelContext {
+        nonce_cache: web::Data::new(nonce_cache.clone()),
+        clipboard_state: web::Data::new(clipboard_state.clone()),
+        rate_limiter: web::Data::new(rate_limiter.clone()),
+        db: db.clone(),
+        channels: channels.clone(),
+    }));
 
     // polling to update peer's addresses between each other
-    tokio::spawn(start_broadcasting(potential_peer_list.clone()));
+    tokio::spawn(start_broadcasting(
+        potential_peer_list.clone(),
+        db.clone(),
+        peer_client.clone(),
+    ));
 
-    // Node
-    HttpServer::new(move || {
+    // Node, reachable by peers
+    let control_clipboard_state = clipboard_state.clone();
+    let control_db = db.clone();
+    let control_peer_client = peer_client.clone();
+    let control_channels = channels.clone();
+    let tls_config = server_config()?;
+    let node_server = HttpServer::new(move || {
         App::new()
             .wrap(Logger::default())
+            .app_data(web::Data::new(nonce_cache.clone()))
+            .app_data(web::Data::new(clipboard_state.clone()))
+            .app_data(web::Data::new(rate_limiter.clone()))
+            .app_data(web::Data::new(db.clone()))
+            .app_data(web::Data::from(peer_client.clone()))
+            .app_data(web::Data::from(channels.clone()))
+            .app_data(update_json_config())
+            .service(echo)
+            .service(pair)
+            .service(pair_accept)
+            .service(pair_reveal)
+            .service(pair_reject)
+            .service(handover)
+            .service(unpaired)
+            .service(update)
+            .service(push_channel)
+    })
+    .on_connect(on_connect)
+    .keep_alive(Duration::from_secs(NODE_KEEP_ALIVE))
+    .bind_rustls_021((config.bind_address, config.node_port), tls_config)?
+    .run();
+
+    // Control API, only for the local user
+    let control_server = HttpServer::new(move || {
+        App::new()
+            .wrap(Logger::default())
+            .app_data(web::Data::new(control_clipboard_state.clone()))
+            .app_data(web::Data::new(control_db.clone()))
+            .app_data(web::Data::from(control_peer_client.clone()))
+            .app_data(web::Data::from(control_channels.clone()))
+            .app_data(update_json_config())
             .app_data(web::Data::clone(&web::Data::new(
                 potential_peer_list.clone(),
             )))
             .service(connect_peer)
-            .service(echo)
             .service(scan)
-            .service(update)
+            .service(pairing_requests)
+            .service(accept_pairing_request)
+            .service(confirm_pairing_request)
+            .service(reject_pairing_request)
+            .service(rotate_keys)
+            .service(unpair_peer)
+            .service(revoke_peer)
+            .service(blocked_peers)
+            .service(unblock_peer)
+            .service(share_withheld)
+            .service(peers)
+            .service(get_peer)
+            .service(update_peer)
+            .service(delete_peer)
+            .service(status)
+            .service(keys)
+            .service(send)
     })
-    .bind(("0.0.0.0", 9898))?
-    .run()
-    .await?;
+    .bind((Ipv4Addr::LOCALHOST, config.control_port))?
+    .run();
+
+    tokio::try_join!(node_server, control_server)?;
 
     Ok(())
 }
 
 async fn pre_run() -> Result<(), Error> {
+    // Keys and database below are created concurrently
+    tokio::fs::create_dir_all(get_data_dir()).await?;
+
     // CPU heavy tasks
     let pre_run_cpu =
         tokio::task::spawn_blocking(move || -> Result<(), Error> {
             check_keys()?;
+            unlock_sign_key()?;
             Ok(())
         });
 
@@ -104,16 +215,35 @@ async fn check_db() -> Result<(), Error> {
 }
 
 #[cfg(target_os = "linux")]
-async fn start_pooling_clipboard() {
+async fn start_pooling_clipboard(
+    clipboard_state: Arc<Mutex<ClipboardState>>,
+    db: Database,
+) {
     let mut clipboard = ClipboardContext::new().unwrap();
     let mut content = clipboard.get_contents().unwrap_or("".to_owned());
     loop {
         tokio::time::sleep(Duration::from_secs(1)).await;
         let new_content = clipboard.get_contents().unwrap_or("".to_owned());
         if content != new_content {
-            log::info!("new clipboard content -> {}", &new_content);
+            log::info!("new clipboard content, {} bytes", new_content.len());
             content = new_content;
-            update_peers(content.clone())
+
+            // Content set by /update came from a peer already
+            let mut state = clipboard_state.lock().await;
+            if state.take_applied(&content) {
+                continue;
+            }
+
+            // Secrets stay local unless the user shares them explicitly
+            if let Some(reason) = detect_secret(&content) {
+                log::info!("Withheld clipboard content, looks like {}", reason);
+                state.withhold(Some(content.clone()));
+                continue;
+            }
+            state.withhold(None);
+            drop(state);
+
+            update_peers(&db, content.clone())
                 .await
                 .unwrap_or_else(|err| log::error!("{}", err));
         }
@@ -121,7 +251,10 @@ async fn start_pooling_clipboard() {
 }
 
 #[cfg(target_os = "android")]
-async fn start_pooling_clipboard() {
+async fn start_pooling_clipboard(
+    clipboard_state: Arc<Mutex<ClipboardState>>,
+    db: Database,
+) {
     todo!()
 }
 
@@ -141,14 +274,28 @@ async fn init_logging() -> Result<(), Error> {
 }
 
 async fn init_socket() -> TokioUdpSocket {
-    let multicast_addr: Ipv4Addr = "239.0.0.1".parse().unwrap();
-    let local_addr: Ipv4Addr = "0.0.0.0".parse().unwrap();
-    let port: u16 = 23235;
-
-    let tokio_socket =
-        TokioUdpSocket::bind(SocketAddrV4::new(local_addr, port))
-            .await
-            .expect("Failed to bind Tokio socket");
+    let config = get_config();
+    let multicast_addr = config.multicast_group;
+    let local_addr = config.bind_address;
+    let port = config.multicast_port;
+
+    // Other instances on this host listen on the same port
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))
+        .expect("Failed to create multicast socket");
+    socket
+        .set_reuse_address(true)
+        .expect("Failed to set SO_REUSEADDR");
+    socket
+        .set_reuse_port(true)
+        .expect("Failed to set SO_REUSEPORT");
+    socket
+        .set_nonblocking(true)
+        .expect("Failed to set socket nonblocking");
+    socket
+        .bind(&SocketAddrV4::new(local_addr, port).into())
+        .expect("Failed to bind Tokio socket");
+    let tokio_socket = TokioUdpSocket::from_std(socket.into())
+        .expect("Failed to bind Tokio socket");
     tokio_socket
         .join_multicast_v4(multicast_addr.clone(), local_addr.clone())
         .expect("Failed to join multicast group for Tokio socket");
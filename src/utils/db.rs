@@ -1,12 +1,17 @@
 use std::fs::OpenOptions;
 
-use crate::entity::peer;
+use crate::entity::{
+    blocked_peer, outbound_update, pairing_request, peer, peer_address,
+};
 use crate::migration::{Migrator, MigratorTrait};
+use crate::utils::encryption::get_fingerprint;
 use crate::utils::error::Error;
-use crate::utils::general::get_db_path;
+use crate::utils::general::{get_db_path, get_timestamp};
 use sea_orm::{
-    ColumnTrait, Database as SeaOrmDatabase, DatabaseConnection, EntityTrait,
-    NotSet, QueryFilter, Set,
+    sea_query::{Expr, OnConflict},
+    ActiveModelTrait, ColumnTrait, Database as SeaOrmDatabase,
+    DatabaseConnection, EntityTrait, NotSet, PaginatorTrait, QueryFilter,
+    QueryOrder, Set,
 };
 
 async fn get_db_pool() -> Result<DatabaseConnection, Error> {
@@ -21,6 +26,33 @@ async fn get_db_pool() -> Result<DatabaseConnection, Error> {
     Ok(pool)
 }
 
+// Addresses that didn't work for this long are dropped, in seconds
+const ADDRESS_MAX_AGE: i64 = 14 * 24 * 60 * 60;
+const MAX_PEER_ADDRESSES: usize = 8;
+// Pairing requests not completed within this long are dropped, in seconds
+const PAIRING_REQUEST_TTL: i64 = 10 * 60;
+
+// Fields left as None keep their value
+#[derive(Default)]
+pub struct PeerChanges {
+    pub nickname: Option<Option<String>>,
+    pub ip: Option<String>,
+    pub port: Option<u16>,
+    pub enabled: Option<bool>,
+    pub trusted: Option<bool>,
+}
+
+pub struct NewPairingRequest<'a> {
+    pub pub_key: &'a str,
+    pub agreement_key: &'a str,
+    pub hostname: &'a str,
+    pub ip: &'a str,
+    pub port: u16,
+    // Our half of the pairing code, and the peer's commitment to its half
+    pub sas_nonce: String,
+    pub sas_commitment: Option<String>,
+}
+
 #[derive(Clone)]
 pub struct Database {
     pool: DatabaseConnection,
@@ -36,46 +68,455 @@ impl Database {
     }
 
     pub async fn insert_peer(
-        &mut self,
+        &self,
         pub_key: &String,
         hostname: &String,
         ip: &String,
+        port: u16,
+        agreement_key: &String,
     ) -> Result<(), Error> {
+        // Pairing again with a known key refreshes how to reach it
+        let peer = peer::ActiveModel {
+            id: NotSet,
+            pub_key: Set(pub_key.to_owned()),
+            hostname: Set(hostname.to_owned()),
+            ip: Set(ip.to_owned()),
+            agreement_key: Set(Some(agreement_key.to_owned())),
+            peer_id: Set(get_fingerprint(pub_key)?),
+            nickname: NotSet,
+            enabled: NotSet,
+            last_seen: NotSet,
+            created_at: Set(get_timestamp() as i64),
+            port: Set(port.into()),
+            trusted: NotSet,
+        };
+        peer::Entity::insert(peer)
+            .on_conflict(
+                OnConflict::column(peer::Column::PubKey)
+                    .update_columns([
+                        peer::Column::Hostname,
+                        peer::Column::Ip,
+                        peer::Column::Port,
+                        peer::Column::AgreementKey,
+                    ])
+                    .to_owned(),
+            )
+            .exec(&self.pool)
+            .await?;
+
         let peer = peer::Entity::find()
-            .filter(peer::Column::PubKey.contains(pub_key))
+            .filter(peer::Column::PubKey.eq(pub_key.as_str()))
+            .one(&self.pool)
+            .await?
+            .ok_or(Error::Generic("Peer disappeared after insert".into()))?;
+        self.add_peer_address(&peer, ip, port).await?;
+        Ok(())
+    }
+    pub async fn get_peers(&self) -> Result<Vec<peer::Model>, Error> {
+        Ok(peer::Entity::find().all(&self.pool).await?)
+    }
+    pub async fn get_peer(
+        &self,
+        peer_id: &str,
+    ) -> Result<Option<peer::Model>, Error> {
+        Ok(peer::Entity::find()
+            .filter(peer::Column::PeerId.eq(peer_id))
+            .one(&self.pool)
+            .await?)
+    }
+    pub async fn peer_seen(
+        &self,
+        peer: peer::Model,
+        ip: &str,
+    ) -> Result<(), Error> {
+        // Address is only a hint, refresh it whenever the peer shows up
+        self.add_peer_address(&peer, ip, peer.port as u16).await?;
+        let mut peer: peer::ActiveModel = peer.into();
+        peer.ip = Set(ip.to_owned());
+        peer.last_seen = Set(Some(get_timestamp() as i64));
+        peer::Entity::update(peer).exec(&self.pool).await?;
+        Ok(())
+    }
+    pub async fn add_peer_address(
+        &self,
+        peer: &peer::Model,
+        ip: &str,
+        port: u16,
+    ) -> Result<peer_address::Model, Error> {
+        let known = peer_address::Entity::find()
+            .filter(peer_address::Column::PeerId.eq(peer.id))
+            .filter(peer_address::Column::Ip.eq(ip))
+            .filter(peer_address::Column::Port.eq(port as i32))
             .one(&self.pool)
             .await?;
-        if let None = peer {
-            let peer = peer::ActiveModel {
-                id: NotSet,
-                pub_key: Set(pub_key.to_owned()),
-                hostname: Set(hostname.to_owned()),
-                ip: Set(ip.to_owned()),
-            };
-            peer::Entity::insert(peer).exec(&self.pool).await?;
+        if let Some(address) = known {
+            return Ok(address);
+        }
+        let address = peer_address::ActiveModel {
+            id: NotSet,
+            peer_id: Set(peer.id),
+            ip: Set(ip.to_owned()),
+            port: Set(port.into()),
+            last_success: NotSet,
+            created_at: Set(get_timestamp() as i64),
+        };
+        let address = address.insert(&self.pool).await?;
+        self.prune_peer_addresses(peer.id, &peer.ip, peer.port)
+            .await?;
+        Ok(address)
+    }
+    // Keeps the addresses used lately, and always the current one
+    async fn prune_peer_addresses(
+        &self,
+        peer_id: i64,
+        ip: &str,
+        port: i32,
+    ) -> Result<(), Error> {
+        let cutoff = get_timestamp() as i64 - ADDRESS_MAX_AGE;
+        let mut addresses = peer_address::Entity::find()
+            .filter(peer_address::Column::PeerId.eq(peer_id))
+            .all(&self.pool)
+            .await?;
+        let used = |address: &peer_address::Model| {
+            address.last_success.unwrap_or(address.created_at)
+        };
+        addresses.sort_by_key(|address| std::cmp::Reverse(used(address)));
+        for (index, address) in addresses.into_iter().enumerate() {
+            let current = address.ip == ip && address.port == port;
+            if !current
+                && (used(&address) < cutoff || index >= MAX_PEER_ADDRESSES)
+            {
+                peer_address::Entity::delete_by_id(address.id)
+                    .exec(&self.pool)
+                    .await?;
+            }
         }
         Ok(())
     }
-    pub async fn get_peers_ip(&self) -> Result<Vec<String>, Error> {
-        let peers: Vec<String> = peer::Entity::find()
+    pub async fn get_peer_addresses(
+        &self,
+        peer: &peer::Model,
+    ) -> Result<Vec<peer_address::Model>, Error> {
+        // Addresses that worked lately first, never reached ones last
+        Ok(peer_address::Entity::find()
+            .filter(peer_address::Column::PeerId.eq(peer.id))
+            .order_by_desc(peer_address::Column::LastSuccess)
+            .order_by_desc(peer_address::Column::Id)
             .all(&self.pool)
+            .await?)
+    }
+    pub async fn peer_reached(
+        &self,
+        address: peer_address::Model,
+    ) -> Result<(), Error> {
+        let now = get_timestamp() as i64;
+        peer::Entity::update_many()
+            .col_expr(peer::Column::Ip, Expr::value(address.ip.clone()))
+            .col_expr(peer::Column::Port, Expr::value(address.port))
+            .col_expr(peer::Column::LastSeen, Expr::value(now))
+            .filter(peer::Column::Id.eq(address.peer_id))
+            .exec(&self.pool)
+            .await?;
+        let (peer_id, ip, port) =
+            (address.peer_id, address.ip.clone(), address.port);
+        let mut address: peer_address::ActiveModel = address.into();
+        address.last_success = Set(Some(now));
+        peer_address::Entity::update(address)
+            .exec(&self.pool)
+            .await?;
+        self.prune_peer_addresses(peer_id, &ip, port).await
+    }
+    pub async fn rotate_peer_key(
+        &self,
+        peer: peer::Model,
+        pub_key: &String,
+        agreement_key: &String,
+    ) -> Result<(), Error> {
+        // A queued update is sealed to the old agreement key, the peer
+        // can't open it anymore
+        outbound_update::Entity::delete_many()
+            .filter(outbound_update::Column::PeerId.eq(peer.id))
+            .exec(&self.pool)
+            .await?;
+
+        // Key, ID and agreement key change together in one statement
+        let mut peer: peer::ActiveModel = peer.into();
+        peer.peer_id = Set(get_fingerprint(pub_key)?);
+        peer.pub_key = Set(pub_key.to_owned());
+        peer.agreement_key = Set(Some(agreement_key.to_owned()));
+        peer::Entity::update(peer).exec(&self.pool).await?;
+        Ok(())
+    }
+    pub async fn update_peer(
+        &self,
+        peer: peer::Model,
+        changes: PeerChanges,
+    ) -> Result<peer::Model, Error> {
+        let mut peer: peer::ActiveModel = peer.into();
+        if let Some(nickname) = changes.nickname {
+            peer.nickname = Set(nickname);
+        }
+        if let Some(ip) = changes.ip {
+            peer.ip = Set(ip);
+        }
+        if let Some(port) = changes.port {
+            peer.port = Set(port.into());
+        }
+        if let Some(enabled) = changes.enabled {
+            peer.enabled = Set(enabled);
+        }
+        if let Some(trusted) = changes.trusted {
+            peer.trusted = Set(trusted);
+        }
+        Ok(peer::Entity::update(peer).exec(&self.pool).await?)
+    }
+    pub async fn delete_peer(&self, peer_id: &str) -> Result<(), Error> {
+        peer::Entity::delete_many()
+            .filter(peer::Column::PeerId.eq(peer_id))
+            .exec(&self.pool)
+            .await?;
+        Ok(())
+    }
+    pub async fn get_peer_by_id(
+        &self,
+        id: i64,
+    ) -> Result<Option<peer::Model>, Error> {
+        Ok(peer::Entity::find_by_id(id).one(&self.pool).await?)
+    }
+    pub async fn queue_update(
+        &self,
+        peer: &peer::Model,
+        message_id: &str,
+        payload: &str,
+    ) -> Result<(), Error> {
+        // Only the latest clipboard is worth delivering, it replaces the last
+        let now = get_timestamp() as i64;
+        let update = outbound_update::ActiveModel {
+            id: NotSet,
+            peer_id: Set(peer.id),
+            message_id: Set(message_id.to_owned()),
+            payload: Set(payload.to_owned()),
+            attempts: Set(0),
+            next_attempt: Set(now),
+            created_at: Set(now),
+        };
+        outbound_update::Entity::insert(update)
+            .on_conflict(
+                OnConflict::column(outbound_update::Column::PeerId)
+                    .update_columns([
+                        outbound_update::Column::MessageId,
+                        outbound_update::Column::Payload,
+                        outbound_update::Column::Attempts,
+                        outbound_update::Column::NextAttempt,
+                        outbound_update::Column::CreatedAt,
+                    ])
+                    .to_owned(),
+            )
+            .exec(&self.pool)
+            .await?;
+        Ok(())
+    }
+    pub async fn get_queued_updates(
+        &self,
+    ) -> Result<Vec<outbound_update::Model>, Error> {
+        Ok(outbound_update::Entity::find().all(&self.pool).await?)
+    }
+    pub async fn remove_queued_update(
+        &self,
+        update: &outbound_update::Model,
+    ) -> Result<(), Error> {
+        // A newer clipboard may have replaced it in the meantime
+        outbound_update::Entity::delete_many()
+            .filter(outbound_update::Column::Id.eq(update.id))
+            .filter(outbound_update::Column::MessageId.eq(&*update.message_id))
+            .exec(&self.pool)
+            .await?;
+        Ok(())
+    }
+    pub async fn postpone_queued_update(
+        &self,
+        update: &outbound_update::Model,
+        delay: u64,
+    ) -> Result<(), Error> {
+        outbound_update::Entity::update_many()
+            .col_expr(
+                outbound_update::Column::Attempts,
+                Expr::value(update.attempts + 1),
+            )
+            .col_expr(
+                outbound_update::Column::NextAttempt,
+                Expr::value(get_timestamp() as i64 + delay as i64),
+            )
+            .filter(outbound_update::Column::Id.eq(update.id))
+            .filter(outbound_update::Column::MessageId.eq(&*update.message_id))
+            .exec(&self.pool)
+            .await?;
+        Ok(())
+    }
+    pub async fn retry_queued_update(
+        &self,
+        peer: &peer::Model,
+    ) -> Result<bool, Error> {
+        let result = outbound_update::Entity::update_many()
+            .col_expr(
+                outbound_update::Column::NextAttempt,
+                Expr::value(get_timestamp() as i64),
+            )
+            .filter(outbound_update::Column::PeerId.eq(peer.id))
+            .exec(&self.pool)
+            .await?;
+        Ok(result.rows_affected > 0)
+    }
+    pub async fn block_peer(
+        &self,
+        peer_id: &str,
+        hostname: &str,
+    ) -> Result<(), Error> {
+        if self.is_blocked(peer_id).await? {
+            return Ok(());
+        }
+        let blocked_peer = blocked_peer::ActiveModel {
+            id: NotSet,
+            peer_id: Set(peer_id.to_owned()),
+            hostname: Set(hostname.to_owned()),
+            created_at: Set(get_timestamp() as i64),
+        };
+        blocked_peer::Entity::insert(blocked_peer)
+            .exec(&self.pool)
+            .await?;
+        Ok(())
+    }
+    pub async fn unblock_peer(&self, peer_id: &str) -> Result<(), Error> {
+        blocked_peer::Entity::delete_many()
+            .filter(blocked_peer::Column::PeerId.eq(peer_id))
+            .exec(&self.pool)
+            .await?;
+        Ok(())
+    }
+    pub async fn is_blocked(&self, peer_id: &str) -> Result<bool, Error> {
+        Ok(blocked_peer::Entity::find()
+            .filter(blocked_peer::Column::PeerId.eq(peer_id))
+            .one(&self.pool)
             .await?
-            .into_iter()
-            .map(|peer| peer.ip)
-            .collect();
-        Ok(peers)
+            .is_some())
+    }
+    pub async fn get_blocked_peers(
+        &self,
+    ) -> Result<Vec<blocked_peer::Model>, Error> {
+        Ok(blocked_peer::Entity::find().all(&self.pool).await?)
+    }
+    pub async fn insert_pairing_request(
+        &self,
+        direction: &str,
+        request: NewPairingRequest<'_>,
+    ) -> Result<(), Error> {
+        self.expire_pairing_requests().await?;
+        // Repeated requests from the same key replace the older one
+        pairing_request::Entity::delete_many()
+            .filter(pairing_request::Column::Direction.eq(direction))
+            .filter(pairing_request::Column::PubKey.eq(request.pub_key))
+            .exec(&self.pool)
+            .await?;
+        let request = pairing_request::ActiveModel {
+            id: NotSet,
+            direction: Set(direction.to_owned()),
+            pub_key: Set(request.pub_key.to_owned()),
+            agreement_key: Set(request.agreement_key.to_owned()),
+            hostname: Set(request.hostname.to_owned()),
+            ip: Set(request.ip.to_owned()),
+            created_at: Set(get_timestamp() as i64),
+            accepted: Set(false),
+            confirmed: Set(false),
+            port: Set(request.port.into()),
+            sas_nonce: Set(request.sas_nonce),
+            peer_sas_nonce: Set(None),
+            sas_commitment: Set(request.sas_commitment),
+        };
+        pairing_request::Entity::insert(request)
+            .exec(&self.pool)
+            .await?;
+        Ok(())
+    }
+    pub async fn get_pairing_requests(
+        &self,
+        direction: &str,
+    ) -> Result<Vec<pairing_request::Model>, Error> {
+        self.expire_pairing_requests().await?;
+        Ok(pairing_request::Entity::find()
+            .filter(pairing_request::Column::Direction.eq(direction))
+            .all(&self.pool)
+            .await?)
     }
-    pub async fn get_peer_pub_key(
+    pub async fn count_pairing_requests(
         &self,
-        peer_ip: &String,
-    ) -> Result<Option<String>, Error> {
-        let peer_record = peer::Entity::find()
-            .filter(peer::Column::Ip.contains(&peer_ip))
+        direction: &str,
+    ) -> Result<u64, Error> {
+        self.expire_pairing_requests().await?;
+        Ok(pairing_request::Entity::find()
+            .filter(pairing_request::Column::Direction.eq(direction))
+            .count(&self.pool)
+            .await?)
+    }
+    pub async fn get_pairing_request(
+        &self,
+        id: i64,
+    ) -> Result<Option<pairing_request::Model>, Error> {
+        self.expire_pairing_requests().await?;
+        Ok(pairing_request::Entity::find_by_id(id)
             .one(&self.pool)
+            .await?)
+    }
+    pub async fn get_pairing_request_by_key(
+        &self,
+        direction: &str,
+        pub_key: &str,
+    ) -> Result<Option<pairing_request::Model>, Error> {
+        self.expire_pairing_requests().await?;
+        Ok(pairing_request::Entity::find()
+            .filter(pairing_request::Column::Direction.eq(direction))
+            .filter(pairing_request::Column::PubKey.eq(pub_key))
+            .one(&self.pool)
+            .await?)
+    }
+    // Every lookup drops stale requests first, so they never show up
+    async fn expire_pairing_requests(&self) -> Result<(), Error> {
+        let cutoff = get_timestamp() as i64 - PAIRING_REQUEST_TTL;
+        pairing_request::Entity::delete_many()
+            .filter(pairing_request::Column::CreatedAt.lt(cutoff))
+            .exec(&self.pool)
             .await?;
-        match peer_record {
-            Some(record) => return Ok(Some(record.pub_key)),
-            None => Ok(None),
-        }
+        Ok(())
+    }
+    pub async fn delete_pairing_request(&self, id: i64) -> Result<(), Error> {
+        pairing_request::Entity::delete_by_id(id)
+            .exec(&self.pool)
+            .await?;
+        Ok(())
+    }
+    pub async fn update_pairing_request(
+        &self,
+        request: pairing_request::Model,
+        accepted: bool,
+        confirmed: bool,
+    ) -> Result<(), Error> {
+        let mut request: pairing_request::ActiveModel = request.into();
+        request.accepted = Set(accepted);
+        request.confirmed = Set(confirmed);
+        pairing_request::Entity::update(request)
+            .exec(&self.pool)
+            .await?;
+        Ok(())
+    }
+    pub async fn reveal_pairing_request(
+        &self,
+        request: pairing_request::Model,
+        peer_sas_nonce: &str,
+    ) -> Result<(), Error> {
+        let mut request: pairing_request::ActiveModel = request.into();
+        request.peer_sas_nonce = Set(Some(peer_sas_nonce.to_owned()));
+        pairing_request::Entity::update(request)
+            .exec(&self.pool)
+            .await?;
+        Ok(())
     }
 }
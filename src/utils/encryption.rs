@@ -1,13 +1,182 @@
 use base64::engine::general_purpose::URL_SAFE_NO_PAD;
 use base64::Engine;
+use lazy_static::lazy_static;
+use pem::Pem;
+use rand::rngs::OsRng;
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, NONCE_LEN};
 use ring::digest;
-use ring::rand::SystemRandom;
+use ring::hkdf;
+use ring::rand::{SecureRandom, SystemRandom};
 use ring::signature::{self, Ed25519KeyPair, KeyPair, UnparsedPublicKey};
-use std::fs;
+use rpassword::prompt_password;
+use scrypt::{scrypt, Params as ScryptParams};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs::{self, File, OpenOptions, Permissions};
+use std::io::{Read, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::sync::{Mutex, RwLock};
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
 
 use crate::utils::{error::Error, general::get_verify_key_path};
 
-use super::general::get_sign_key_path;
+use super::general::{
+    get_agreement_key_path, get_agreement_pub_key_path, get_api_token_path,
+    get_key_install_path, get_sign_key_path,
+};
+use super::tls::build_client_as;
+
+const PAYLOAD_INFO: &[u8] = b"resk clipboard payload v1";
+const SAS_SALT: &[u8] = b"resk sas v2";
+const SIGN_KEY_TAG: &str = "PRIVATE KEY";
+const ENCRYPTED_SIGN_KEY_TAG: &str = "RESK ENCRYPTED PRIVATE KEY";
+const SCRYPT_LOG_N: u8 = 15;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+
+lazy_static! {
+    // Unlocked sign key, it never touches the disk in plain form
+    static ref SIGN_KEY: RwLock<Option<UnlockedSignKey>> = RwLock::new(None);
+    static ref FD_PASSPHRASE: Mutex<Option<String>> = Mutex::new(None);
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EncryptedPayload {
+    pub ephemeral_key: String,
+    pub nonce: String,
+    pub ciphertext: String,
+}
+
+impl EncryptedPayload {
+    // Size of the clipboard inside, without decrypting it
+    pub fn plaintext_len(&self) -> usize {
+        (self.ciphertext.len() * 3 / 4).saturating_sub(aead::MAX_TAG_LEN)
+    }
+
+    // Content covered by the sender's signature
+    pub fn signed_content(&self) -> String {
+        format!("{}.{}.{}", self.ephemeral_key, self.nonce, self.ciphertext)
+    }
+}
+
+struct UnlockedSignKey {
+    pkcs8: Vec<u8>,
+    // Kept so a rotated key is stored the same way
+    passphrase: Option<String>,
+}
+
+// Fresh identity, held in memory until it replaces the current one
+pub struct NodeKeys {
+    pkcs8: Vec<u8>,
+    agreement_key: StaticSecret,
+    pub verify_key: String,
+    pub agreement_pub_key: String,
+}
+
+impl NodeKeys {
+    pub fn generate() -> Result<Self, Error> {
+        let rng = SystemRandom::new();
+        let pkcs8_bytes = Ed25519KeyPair::generate_pkcs8(&rng)?;
+        let sign_key = Ed25519KeyPair::from_pkcs8(pkcs8_bytes.as_ref())?;
+        let agreement_key = StaticSecret::random_from_rng(OsRng);
+        let agreement_pub_key = PublicKey::from(&agreement_key);
+        Ok(Self {
+            pkcs8: pkcs8_bytes.as_ref().to_vec(),
+            agreement_key,
+            verify_key: URL_SAFE_NO_PAD.encode(sign_key.public_key()),
+            agreement_pub_key: URL_SAFE_NO_PAD
+                .encode(agreement_pub_key.as_bytes()),
+        })
+    }
+
+    pub fn sign(&self, msg: &str) -> Result<String, Error> {
+        let sign_key = Ed25519KeyPair::from_pkcs8(&self.pkcs8)?;
+        Ok(URL_SAFE_NO_PAD.encode(sign_key.sign(msg.as_bytes())))
+    }
+
+    // Client presenting these keys, for peers that already switched to them
+    pub fn client(
+        &self,
+        pinned_key: &String,
+    ) -> Result<reqwest::Client, Error> {
+        build_client_as(self.pkcs8.clone(), &self.verify_key, pinned_key)
+    }
+
+    // Replaces the node's identity on disk and in memory
+    pub fn install(self) -> Result<(), Error> {
+        let passphrase = SIGN_KEY
+            .read()
+            .unwrap()
+            .as_ref()
+            .and_then(|sign_key| sign_key.passphrase.clone());
+        let sign_key = encode_sign_key(&self.pkcs8, passphrase.clone())?;
+        let staged = [
+            stage_file(get_sign_key_path(), sign_key.as_bytes(), 0o600)?,
+            stage_file(
+                get_verify_key_path(),
+                &URL_SAFE_NO_PAD.decode(&self.verify_key)?,
+                0o644,
+            )?,
+            stage_file(
+                get_agreement_key_path(),
+                &self.agreement_key.to_bytes(),
+                0o600,
+            )?,
+            stage_file(
+                get_agreement_pub_key_path(),
+                &URL_SAFE_NO_PAD.decode(&self.agreement_pub_key)?,
+                0o644,
+            )?,
+        ];
+        // Once the marker is down the set is complete, a crash while
+        // replacing the keys is finished on the next start
+        write_private_file(
+            &get_key_install_path(),
+            self.verify_key.as_bytes(),
+        )?;
+        for (path, staged_path) in staged {
+            fs::rename(staged_path, path)?;
+        }
+        fs::remove_file(get_key_install_path())?;
+        *SIGN_KEY.write().unwrap() = Some(UnlockedSignKey {
+            pkcs8: self.pkcs8,
+            passphrase,
+        });
+        Ok(())
+    }
+}
+
+fn key_paths() -> [String; 4] {
+    [
+        get_sign_key_path(),
+        get_verify_key_path(),
+        get_agreement_key_path(),
+        get_agreement_pub_key_path(),
+    ]
+}
+
+// Completes a key rotation that was cut short, or drops one that never
+// got all of its keys staged
+pub fn recover_key_install() -> Result<(), Error> {
+    let committed = fs::metadata(get_key_install_path()).is_ok();
+    for path in key_paths() {
+        let staged_path = format!("{}.tmp", path);
+        if fs::metadata(&staged_path).is_err() {
+            continue;
+        }
+        if committed {
+            fs::rename(staged_path, path)?;
+        } else {
+            fs::remove_file(staged_path)?;
+        }
+    }
+    if committed {
+        fs::remove_file(get_key_install_path())?;
+        log::info!("Finished an interrupted key rotation");
+    }
+    Ok(())
+}
 
 pub fn generate_keys() -> Result<(), Error> {
     // Generate keys
@@ -16,7 +185,7 @@ pub fn generate_keys() -> Result<(), Error> {
 
     // Save private key
     let sign_key = Ed25519KeyPair::from_pkcs8(pkcs8_bytes.as_ref())?;
-    fs::write(get_sign_key_path(), &pkcs8_bytes)?;
+    write_sign_key(pkcs8_bytes.as_ref(), read_passphrase()?)?;
 
     // Save public key
     let verify_key = sign_key.public_key().as_ref();
@@ -25,8 +194,241 @@ pub fn generate_keys() -> Result<(), Error> {
     Ok(())
 }
 
+pub fn generate_agreement_keys() -> Result<(), Error> {
+    // Generate keys
+    let agreement_key = StaticSecret::random_from_rng(OsRng);
+
+    // Save private key
+    write_private_file(&get_agreement_key_path(), &agreement_key.to_bytes())?;
+
+    // Save public key
+    let agreement_pub_key = PublicKey::from(&agreement_key);
+    fs::write(get_agreement_pub_key_path(), agreement_pub_key.as_bytes())?;
+
+    Ok(())
+}
+
+pub fn generate_api_token() -> Result<(), Error> {
+    let mut token = [0u8; 32];
+    SystemRandom::new().fill(&mut token)?;
+
+    write_private_file(
+        &get_api_token_path(),
+        URL_SAFE_NO_PAD.encode(token).as_bytes(),
+    )
+}
+
+pub fn load_api_token() -> Result<String, Error> {
+    Ok(fs::read_to_string(get_api_token_path())?.trim().to_owned())
+}
+
+fn encode_sign_key(
+    pkcs8: &[u8],
+    passphrase: Option<String>,
+) -> Result<String, Error> {
+    let sign_key = match passphrase {
+        Some(passphrase) => encrypt_sign_key(pkcs8, &passphrase)?,
+        None => Pem::new(SIGN_KEY_TAG, pkcs8),
+    };
+    Ok(pem::encode(&sign_key))
+}
+
+fn write_sign_key(
+    pkcs8: &[u8],
+    passphrase: Option<String>,
+) -> Result<(), Error> {
+    let sign_key = encode_sign_key(pkcs8, passphrase)?;
+    write_private_file(&get_sign_key_path(), sign_key.as_bytes())
+}
+
+// Full contents next to the file, so a crash never leaves it half written
+fn stage_file(
+    path: String,
+    contents: &[u8],
+    mode: u32,
+) -> Result<(String, String), Error> {
+    let staged_path = format!("{}.tmp", path);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(&staged_path)?;
+    // A leftover staged file keeps the mode it was created with
+    file.set_permissions(Permissions::from_mode(mode))?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    Ok((path, staged_path))
+}
+
+// Only the owner may read private keys and the token
+fn write_private_file(path: &str, contents: &[u8]) -> Result<(), Error> {
+    let (path, staged_path) = stage_file(path.to_owned(), contents, 0o600)?;
+    fs::rename(staged_path, path)?;
+    Ok(())
+}
+
+// Tightens a private file left behind by an older version
+pub fn restrict_private_file(path: &str) -> Result<(), Error> {
+    fs::set_permissions(path, Permissions::from_mode(0o600))?;
+    Ok(())
+}
+
+fn derive_sign_key_wrap(
+    passphrase: &str,
+    salt: &[u8],
+    params: &ScryptParams,
+) -> Result<LessSafeKey, Error> {
+    let mut key = [0u8; 32];
+    scrypt(passphrase.as_bytes(), salt, params, &mut key)
+        .map_err(|err| Error::Generic(err.into()))?;
+    Ok(LessSafeKey::new(UnboundKey::new(
+        &aead::CHACHA20_POLY1305,
+        &key,
+    )?))
+}
+
+fn encrypt_sign_key(pkcs8: &[u8], passphrase: &str) -> Result<Pem, Error> {
+    let rng = SystemRandom::new();
+    let mut salt = [0u8; 16];
+    rng.fill(&mut salt)?;
+    let mut nonce = [0u8; NONCE_LEN];
+    rng.fill(&mut nonce)?;
+
+    let params = ScryptParams::new(
+        SCRYPT_LOG_N,
+        SCRYPT_R,
+        SCRYPT_P,
+        ScryptParams::RECOMMENDED_LEN,
+    )
+    .map_err(|err| Error::Generic(err.into()))?;
+    let wrap_key = derive_sign_key_wrap(passphrase, &salt, &params)?;
+    let mut in_out = pkcs8.to_vec();
+    wrap_key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(ENCRYPTED_SIGN_KEY_TAG),
+        &mut in_out,
+    )?;
+
+    // KDF parameters travel with the key, so they can be raised later
+    let mut sign_key = Pem::new(ENCRYPTED_SIGN_KEY_TAG, in_out);
+    let kdf = format!("scrypt,{},{},{}", SCRYPT_LOG_N, SCRYPT_R, SCRYPT_P);
+    sign_key.headers_mut().add("Kdf", &kdf)?;
+    sign_key
+        .headers_mut()
+        .add("Salt", &URL_SAFE_NO_PAD.encode(salt))?;
+    sign_key
+        .headers_mut()
+        .add("Nonce", &URL_SAFE_NO_PAD.encode(nonce))?;
+    Ok(sign_key)
+}
+
+fn decrypt_sign_key(
+    sign_key: &Pem,
+    passphrase: &str,
+) -> Result<Vec<u8>, Error> {
+    let header = |name: &str| {
+        sign_key.headers().get(name).ok_or(Error::Generic(
+            format!("Sign key has no {} header", name).into(),
+        ))
+    };
+    let kdf: Vec<&str> = header("Kdf")?.split(',').collect();
+    let params = match kdf.as_slice() {
+        ["scrypt", log_n, r, p] => ScryptParams::new(
+            log_n.parse().unwrap_or(0),
+            r.parse().unwrap_or(0),
+            p.parse().unwrap_or(0),
+            ScryptParams::RECOMMENDED_LEN,
+        )
+        .map_err(|err| Error::Generic(err.into()))?,
+        _ => return Err(Error::Generic("Unsupported sign key KDF".into())),
+    };
+    let salt = URL_SAFE_NO_PAD.decode(header("Salt")?)?;
+    let nonce = Nonce::try_assume_unique_for_key(
+        &URL_SAFE_NO_PAD.decode(header("Nonce")?)?,
+    )?;
+
+    let wrap_key = derive_sign_key_wrap(passphrase, &salt, &params)?;
+    let mut in_out = sign_key.contents().to_vec();
+    let pkcs8 = wrap_key
+        .open_in_place(nonce, Aad::from(ENCRYPTED_SIGN_KEY_TAG), &mut in_out)
+        .map_err(|_| Error::Generic("Wrong passphrase for sign key".into()))?;
+    Ok(pkcs8.to_vec())
+}
+
+// Passphrase given to the daemon, if any, without asking the user
+fn read_passphrase() -> Result<Option<String>, Error> {
+    if let Ok(passphrase) = env::var("RESK_PASSPHRASE") {
+        return Ok(Some(passphrase));
+    }
+    if let Ok(fd) = env::var("RESK_PASSPHRASE_FD") {
+        // A descriptor can only be read once
+        let mut cached = FD_PASSPHRASE.lock().unwrap();
+        if cached.is_none() {
+            let fd: RawFd = fd.parse().map_err(|_| {
+                Error::Generic("Malformed RESK_PASSPHRASE_FD".into())
+            })?;
+            // The descriptor is handed to us by whoever started the daemon
+            let mut passphrase = String::new();
+            unsafe { File::from_raw_fd(fd) }.read_to_string(&mut passphrase)?;
+            *cached = Some(passphrase.lines().next().unwrap_or("").to_owned());
+        }
+        return Ok(cached.clone());
+    }
+    Ok(None)
+}
+
+pub fn unlock_sign_key() -> Result<(), Error> {
+    let sign_key_bytes = fs::read(get_sign_key_path())?;
+    // Keys stored the old way are rewritten, once they proved to be valid
+    let (pkcs8, passphrase, rewrite) = match pem::parse(&sign_key_bytes) {
+        Ok(sign_key) if sign_key.tag() == ENCRYPTED_SIGN_KEY_TAG => {
+            let passphrase = match read_passphrase()? {
+                Some(passphrase) => passphrase,
+                None => prompt_password("Passphrase for sign key: ")?,
+            };
+            let pkcs8 = decrypt_sign_key(&sign_key, &passphrase)?;
+            (pkcs8, Some(passphrase), false)
+        }
+        Ok(sign_key) if sign_key.tag() == SIGN_KEY_TAG => {
+            // Starting with a passphrase encrypts a plain key
+            let passphrase = read_passphrase()?;
+            let rewrite = passphrase.is_some();
+            (sign_key.into_contents(), passphrase, rewrite)
+        }
+        Ok(sign_key) => {
+            return Err(Error::Generic(
+                format!("Unexpected sign key type {}", sign_key.tag()).into(),
+            ))
+        }
+        Err(_) => {
+            // Older versions stored raw PKCS#8 bytes
+            (sign_key_bytes, read_passphrase()?, true)
+        }
+    };
+
+    Ed25519KeyPair::from_pkcs8(&pkcs8)?;
+    if rewrite {
+        write_sign_key(&pkcs8, passphrase.clone())?;
+        if passphrase.is_some() {
+            log::info!("Sign key encrypted with passphrase");
+        }
+    }
+    *SIGN_KEY.write().unwrap() = Some(UnlockedSignKey { pkcs8, passphrase });
+    Ok(())
+}
+
+pub fn load_sign_key_pkcs8() -> Result<Vec<u8>, Error> {
+    SIGN_KEY
+        .read()
+        .unwrap()
+        .as_ref()
+        .map(|sign_key| sign_key.pkcs8.clone())
+        .ok_or(Error::Generic("Sign key is locked".into()))
+}
+
 fn load_sign_key() -> Result<Ed25519KeyPair, Error> {
-    let sign_key_bytes = &fs::read(get_sign_key_path())?;
+    let sign_key_bytes = &load_sign_key_pkcs8()?;
     let sign_key = Ed25519KeyPair::from_pkcs8(&sign_key_bytes)?;
     Ok(sign_key)
 }
@@ -41,6 +443,37 @@ fn load_verify_key(
     Ok(verify_key)
 }
 
+fn load_agreement_key() -> Result<StaticSecret, Error> {
+    let agreement_key_bytes: [u8; 32] = fs::read(get_agreement_key_path())?
+        .try_into()
+        .map_err(|_| Error::Generic("Malformed agreement key".into()))?;
+    Ok(StaticSecret::from(agreement_key_bytes))
+}
+
+fn decode_agreement_pub_key(
+    agreement_key: &String,
+) -> Result<PublicKey, Error> {
+    let agreement_key_bytes: [u8; 32] = URL_SAFE_NO_PAD
+        .decode(agreement_key)?
+        .try_into()
+        .map_err(|_| Error::Generic("Malformed agreement key".into()))?;
+    Ok(PublicKey::from(agreement_key_bytes))
+}
+
+fn derive_payload_key(
+    shared_secret: &[u8],
+    ephemeral_key: &PublicKey,
+    recipient_key: &PublicKey,
+) -> Result<LessSafeKey, Error> {
+    // Bind the derived key to both public halves of the exchange
+    let mut salt = ephemeral_key.as_bytes().to_vec();
+    salt.extend_from_slice(recipient_key.as_bytes());
+
+    let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, &salt).extract(shared_secret);
+    let okm = prk.expand(&[PAYLOAD_INFO], &aead::CHACHA20_POLY1305)?;
+    Ok(LessSafeKey::new(UnboundKey::from(okm)))
+}
+
 pub async fn sign_message(msg: &String) -> Result<String, Error> {
     let sign_key = load_sign_key()?;
     let sig = sign_key.sign(msg.as_bytes());
@@ -58,10 +491,149 @@ pub async fn verify_message(
     Ok(())
 }
 
-pub async fn get_digest(msg: &str) -> String {
-    digest::digest(&digest::SHA512, msg.as_bytes())
+pub async fn encrypt_message(
+    agreement_key: &String,
+    msg: &String,
+) -> Result<EncryptedPayload, Error> {
+    // One-off key pair per message, so payloads can't be linked
+    let recipient_key = decode_agreement_pub_key(agreement_key)?;
+    let ephemeral_secret = EphemeralSecret::random_from_rng(OsRng);
+    let ephemeral_key = PublicKey::from(&ephemeral_secret);
+    let shared_secret = ephemeral_secret.diffie_hellman(&recipient_key);
+    let payload_key = derive_payload_key(
+        shared_secret.as_bytes(),
+        &ephemeral_key,
+        &recipient_key,
+    )?;
+
+    // Seal clipboard
+    let mut nonce = [0u8; NONCE_LEN];
+    SystemRandom::new().fill(&mut nonce)?;
+    let mut in_out = msg.as_bytes().to_vec();
+    payload_key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::empty(),
+        &mut in_out,
+    )?;
+
+    Ok(EncryptedPayload {
+        ephemeral_key: URL_SAFE_NO_PAD.encode(ephemeral_key.as_bytes()),
+        nonce: URL_SAFE_NO_PAD.encode(nonce),
+        ciphertext: URL_SAFE_NO_PAD.encode(in_out),
+    })
+}
+
+pub async fn decrypt_message(
+    payload: &EncryptedPayload,
+) -> Result<String, Error> {
+    let agreement_key = load_agreement_key()?;
+    let recipient_key = PublicKey::from(&agreement_key);
+    let ephemeral_key = decode_agreement_pub_key(&payload.ephemeral_key)?;
+    let shared_secret = agreement_key.diffie_hellman(&ephemeral_key);
+    let payload_key = derive_payload_key(
+        shared_secret.as_bytes(),
+        &ephemeral_key,
+        &recipient_key,
+    )?;
+
+    // Open clipboard
+    let nonce = Nonce::try_assume_unique_for_key(
+        &URL_SAFE_NO_PAD.decode(&payload.nonce)?,
+    )?;
+    let mut in_out = URL_SAFE_NO_PAD.decode(&payload.ciphertext)?;
+    let plaintext =
+        payload_key.open_in_place(nonce, Aad::empty(), &mut in_out)?;
+    Ok(String::from_utf8(plaintext.to_vec())?)
+}
+
+pub fn generate_nonce() -> Result<String, Error> {
+    let mut nonce = [0u8; 16];
+    SystemRandom::new().fill(&mut nonce)?;
+    Ok(URL_SAFE_NO_PAD.encode(nonce))
+}
+
+pub fn get_fingerprint(verify_key: &String) -> Result<String, Error> {
+    let verify_key_bytes = URL_SAFE_NO_PAD.decode(verify_key)?;
+    Ok(digest::digest(&digest::SHA256, &verify_key_bytes)
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>())
+}
+
+pub fn get_content_hash(content: &str) -> String {
+    digest::digest(&digest::SHA256, content.as_bytes())
         .as_ref()
         .iter()
         .map(|byte| format!("{:02x}", byte))
         .collect::<String>()
 }
+
+pub fn get_verify_key() -> Result<String, Error> {
+    let verify_key_bytes = fs::read(get_verify_key_path())?;
+    Ok(URL_SAFE_NO_PAD.encode(verify_key_bytes))
+}
+
+pub fn get_node_id() -> Result<String, Error> {
+    get_fingerprint(&get_verify_key()?)
+}
+
+// Short code both users compare while pairing, so a swapped key shows up
+pub fn get_sas(
+    verify_key: &String,
+    peer_verify_key: &String,
+    sas_nonce: &str,
+    peer_sas_nonce: &str,
+) -> Result<String, Error> {
+    let mut keys = [
+        URL_SAFE_NO_PAD.decode(verify_key)?,
+        URL_SAFE_NO_PAD.decode(peer_verify_key)?,
+    ];
+    keys.sort();
+    let mut nonces = [
+        URL_SAFE_NO_PAD.decode(sas_nonce)?,
+        URL_SAFE_NO_PAD.decode(peer_sas_nonce)?,
+    ];
+    nonces.sort();
+
+    // The requester commits to its nonce before seeing ours, so a
+    // swapped key matches the code by chance only, one try per pairing
+    let mut context = digest::Context::new(&digest::SHA256);
+    context.update(SAS_SALT);
+    context.update(&keys.concat());
+    context.update(&nonces.concat());
+    let digest = context.finish();
+    let code: [u8; 4] = digest.as_ref()[..4].try_into().unwrap();
+    let code = u32::from_be_bytes(code) % 1_000_000;
+    Ok(format!("{:03} {:03}", code / 1000, code % 1000))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sas_is_the_same_on_both_sides() {
+        let key = URL_SAFE_NO_PAD.encode([1u8; 32]);
+        let peer_key = URL_SAFE_NO_PAD.encode([2u8; 32]);
+        let nonce = generate_nonce().unwrap();
+        let peer_nonce = generate_nonce().unwrap();
+        assert_eq!(
+            get_sas(&key, &peer_key, &nonce, &peer_nonce).unwrap(),
+            get_sas(&peer_key, &key, &peer_nonce, &nonce).unwrap()
+        );
+    }
+
+    #[test]
+    fn sas_changes_with_a_swapped_key() {
+        let key = URL_SAFE_NO_PAD.encode([1u8; 32]);
+        let peer_key = URL_SAFE_NO_PAD.encode([2u8; 32]);
+        let swapped_key = URL_SAFE_NO_PAD.encode([3u8; 32]);
+        let nonce = URL_SAFE_NO_PAD.encode([4u8; 16]);
+        let peer_nonce = URL_SAFE_NO_PAD.encode([5u8; 16]);
+        assert_ne!(
+            get_sas(&key, &peer_key, &nonce, &peer_nonce).unwrap(),
+            get_sas(&key, &swapped_key, &nonce, &peer_nonce).unwrap()
+        );
+    }
+}
@@ -4,6 +4,8 @@ use crate::migration::DbErr;
 use base64::DecodeError;
 use log::SetLoggerError;
 use log4rs::config::runtime::ConfigErrors;
+use pem::PemError;
+use rcgen::RcgenError;
 use ring::error::{KeyRejected, Unspecified};
 use tokio::task::JoinError;
 
@@ -22,6 +24,11 @@ pub enum Error {
     FromUtf8(FromUtf8Error),
     BaseDecode(DecodeError),
     SerdeJson(serde_json::Error),
+    Tls(rustls::Error),
+    Certificate(RcgenError),
+    Pem(PemError),
+    ConfigFile(toml::de::Error),
+    Daemon(String),
 }
 
 impl std::fmt::Display for Error {
@@ -66,6 +73,21 @@ impl std::fmt::Display for Error {
             Self::SerdeJson(ref err) => {
                 write!(f, "Error decoding json: {}", err)
             }
+            Self::Tls(ref err) => {
+                write!(f, "TLS error: {}", err)
+            }
+            Self::Certificate(ref err) => {
+                write!(f, "Certificate error: {}", err)
+            }
+            Self::Pem(ref err) => {
+                write!(f, "PEM error: {}", err)
+            }
+            Self::ConfigFile(ref err) => {
+                write!(f, "Error reading config.toml: {}", err)
+            }
+            Self::Daemon(ref msg) => {
+                write!(f, "{}", msg)
+            }
         }
     }
 }
@@ -147,3 +169,27 @@ impl From<serde_json::Error> for Error {
         Self::SerdeJson(err)
     }
 }
+
+impl From<rustls::Error> for Error {
+    fn from(err: rustls::Error) -> Self {
+        Self::Tls(err)
+    }
+}
+
+impl From<RcgenError> for Error {
+    fn from(err: RcgenError) -> Self {
+        Self::Certificate(err)
+    }
+}
+
+impl From<PemError> for Error {
+    fn from(err: PemError) -> Self {
+        Self::Pem(err)
+    }
+}
+
+impl From<toml::de::Error> for Error {
+    fn from(err: toml::de::Error) -> Self {
+        Self::ConfigFile(err)
+    }
+}
@@ -1,10 +1,35 @@
-use std::{fs::OpenOptions, path::Path};
+use std::{
+    env,
+    fs::OpenOptions,
+    path::Path,
+    sync::RwLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use actix_web::{HttpRequest, HttpResponse};
+use actix_web::{http::header::AUTHORIZATION, HttpRequest, HttpResponse};
 use dirs::home_dir;
+use lazy_static::lazy_static;
+use ring::constant_time::verify_slices_are_equal;
 use serde::{Deserialize, Serialize};
 
-use super::{encryption::generate_keys, error::Error};
+use super::{
+    config::get_config,
+    encryption::{
+        generate_agreement_keys, generate_api_token, generate_keys,
+        load_api_token, recover_key_install, restrict_private_file,
+    },
+    error::Error,
+};
+
+lazy_static! {
+    static ref DATA_DIR: RwLock<Option<String>> = RwLock::new(None);
+}
+
+// Max clock difference between peers, in seconds
+pub const MAX_MESSAGE_AGE: u64 = 60;
+
+// Header every node sends with the fingerprint of its verify key
+pub const PEER_ID_HEADER: &str = "X-Resk-Peer-Id";
 
 #[derive(Serialize, Deserialize)]
 struct SuccessResponse<T> {
@@ -33,10 +58,26 @@ impl Response {
                 success: false,
                 msg,
             }),
+            401 => HttpResponse::Unauthorized().json(FailureResponse {
+                success: false,
+                msg,
+            }),
             403 => HttpResponse::Forbidden().json(FailureResponse {
                 success: false,
                 msg,
             }),
+            404 => HttpResponse::NotFound().json(FailureResponse {
+                success: false,
+                msg,
+            }),
+            413 => HttpResponse::PayloadTooLarge().json(FailureResponse {
+                success: false,
+                msg,
+            }),
+            429 => HttpResponse::TooManyRequests().json(FailureResponse {
+                success: false,
+                msg,
+            }),
             500 => HttpResponse::InternalServerError().json(FailureResponse {
                 success: false,
                 msg,
@@ -50,12 +91,24 @@ impl Response {
 }
 
 pub fn check_keys() -> Result<(), Error> {
+    recover_key_install()?;
     if !Path::new(get_verify_key_path().as_str()).exists()
         || !Path::new(get_sign_key_path().as_str()).exists()
     {
         std::fs::create_dir_all(get_keys_dir())?;
         generate_keys()?;
     }
+    if !Path::new(get_agreement_pub_key_path().as_str()).exists()
+        || !Path::new(get_agreement_key_path().as_str()).exists()
+    {
+        std::fs::create_dir_all(get_keys_dir())?;
+        generate_agreement_keys()?;
+    } else {
+        restrict_private_file(&get_agreement_key_path())?;
+    }
+    if !Path::new(get_api_token_path().as_str()).exists() {
+        generate_api_token()?;
+    }
     if !Path::new(get_log_file_path().as_str()).exists() {
         OpenOptions::new()
             .write(true)
@@ -72,29 +125,79 @@ pub fn get_home_dir() -> String {
     home_dir.to_string_lossy().to_string()
 }
 
+// Set from --data-dir, takes precedence over RESK_HOME
+pub fn set_data_dir(data_dir: String) {
+    *DATA_DIR.write().unwrap() = Some(data_dir);
+}
+
+#[cfg(target_os = "linux")]
+pub fn get_data_dir() -> String {
+    DATA_DIR
+        .read()
+        .unwrap()
+        .clone()
+        .or(env::var("RESK_HOME").ok())
+        .unwrap_or(format!("{}/.resk", get_home_dir()))
+}
+
 #[cfg(target_os = "linux")]
 pub fn get_keys_dir() -> String {
-    format!("{}/.resk/keys", get_home_dir())
+    format!("{}/keys", get_data_dir())
 }
 
 #[cfg(target_os = "linux")]
 pub fn get_verify_key_path() -> String {
-    format!("{}/.resk/keys/verify_key.pem", get_home_dir())
+    format!("{}/keys/verify_key.pem", get_data_dir())
 }
 
 #[cfg(target_os = "linux")]
 pub fn get_sign_key_path() -> String {
-    format!("{}/.resk/keys/sign_key.pem", get_home_dir())
+    format!("{}/keys/sign_key.pem", get_data_dir())
+}
+
+#[cfg(target_os = "linux")]
+pub fn get_agreement_pub_key_path() -> String {
+    format!("{}/keys/agreement_pub_key.pem", get_data_dir())
+}
+
+#[cfg(target_os = "linux")]
+pub fn get_agreement_key_path() -> String {
+    format!("{}/keys/agreement_key.pem", get_data_dir())
+}
+
+// Present while a rotated key set is being moved into place
+#[cfg(target_os = "linux")]
+pub fn get_key_install_path() -> String {
+    format!("{}/keys/install.pending", get_data_dir())
+}
+
+#[cfg(target_os = "linux")]
+pub fn get_api_token_path() -> String {
+    format!("{}/api_token", get_data_dir())
+}
+
+#[cfg(target_os = "linux")]
+pub fn get_secret_patterns_path() -> String {
+    format!("{}/secret_patterns", get_data_dir())
+}
+
+#[cfg(target_os = "linux")]
+pub fn get_config_path() -> String {
+    format!("{}/config.toml", get_data_dir())
 }
 
 #[cfg(target_os = "linux")]
 pub fn get_db_path() -> String {
-    format!("{}/.resk/resk_db.sqlite", get_home_dir())
+    get_config()
+        .db_path
+        .unwrap_or(format!("{}/resk_db.sqlite", get_data_dir()))
 }
 
 #[cfg(target_os = "linux")]
 pub fn get_log_file_path() -> String {
-    format!("{}/.resk/resk.log", get_home_dir())
+    get_config()
+        .log_file
+        .unwrap_or(format!("{}/resk.log", get_data_dir()))
 }
 
 #[cfg(target_os = "android")]
@@ -102,6 +205,11 @@ pub fn get_home_dir() -> String {
     todo!()
 }
 
+#[cfg(target_os = "android")]
+pub fn get_data_dir() -> String {
+    todo!()
+}
+
 #[cfg(target_os = "android")]
 pub fn get_keys_dir() -> String {
     todo!()
@@ -117,6 +225,36 @@ pub fn _get_sign_key_path() -> String {
     todo!()
 }
 
+#[cfg(target_os = "android")]
+pub fn get_agreement_pub_key_path() -> String {
+    todo!()
+}
+
+#[cfg(target_os = "android")]
+pub fn get_agreement_key_path() -> String {
+    todo!()
+}
+
+#[cfg(target_os = "android")]
+pub fn get_key_install_path() -> String {
+    todo!()
+}
+
+#[cfg(target_os = "android")]
+pub fn get_api_token_path() -> String {
+    todo!()
+}
+
+#[cfg(target_os = "android")]
+pub fn get_secret_patterns_path() -> String {
+    todo!()
+}
+
+#[cfg(target_os = "android")]
+pub fn get_config_path() -> String {
+    todo!()
+}
+
 #[cfg(target_os = "android")]
 pub fn get_db_path() -> String {
     todo!()
@@ -127,9 +265,43 @@ pub fn get_log_file_path() -> String {
     todo!()
 }
 
+// Control endpoints require the token from the api_token file
+pub fn is_authorized(req: &HttpRequest) -> bool {
+    let token = match load_api_token() {
+        Ok(token) => token,
+        Err(_) => return false,
+    };
+    req.headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|value| {
+            verify_slices_are_equal(value.as_bytes(), token.as_bytes()).is_ok()
+        })
+        .unwrap_or(false)
+}
+
+// Address of the TCP peer, forwarding headers are set by the sender
 pub async fn get_remote_ip(req: &HttpRequest) -> String {
-    req.connection_info()
-        .realip_remote_addr()
-        .unwrap_or("127.0.0.1")
-        .to_string()
+    req.peer_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or("127.0.0.1".to_owned())
+}
+
+pub fn get_peer_id(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get(PEER_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_owned())
+}
+
+pub fn is_fresh(timestamp: u64) -> bool {
+    get_timestamp().abs_diff(timestamp) <= MAX_MESSAGE_AGE
+}
+
+pub fn get_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
 }
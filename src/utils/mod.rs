@@ -1,6 +1,10 @@
+pub mod channel;
 pub mod communication;
+pub mod config;
 pub mod controllers;
 pub mod db;
 pub mod encryption;
 pub mod error;
 pub mod general;
+pub mod secrets;
+pub mod tls;
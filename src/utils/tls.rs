@@ -0,0 +1,344 @@
+use actix_tls::accept::rustls_0_21::TlsStream;
+use actix_web::dev::Extensions;
+use actix_web::rt::net::TcpStream;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rcgen::{CertificateParams, KeyPair, PKCS_ED25519};
+use reqwest::header::{HeaderMap, HeaderValue};
+use reqwest::tls::TlsInfo;
+use reqwest::{Client, Response};
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::server::{
+    ClientCertVerified, ClientCertVerifier, ClientHello, ResolvesServerCert,
+};
+use rustls::sign::{any_eddsa_type, CertifiedKey};
+use rustls::{
+    Certificate, CertificateError, ClientConfig, DistinguishedName, PrivateKey,
+    ServerConfig, ServerName,
+};
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+use tokio::time::Duration;
+use x509_parser::certificate::X509Certificate;
+use x509_parser::oid_registry::OID_SIG_ED25519;
+use x509_parser::prelude::FromDer;
+
+use crate::utils::encryption::{
+    get_fingerprint, get_node_id, load_sign_key_pkcs8,
+};
+use crate::utils::error::Error;
+use crate::utils::general::PEER_ID_HEADER;
+
+// Verify key of the peer on the other end of a TLS connection
+#[derive(Clone)]
+pub struct PeerIdentity(pub String);
+
+// Certificates are self-signed, so trust comes from the key inside them.
+// With a pinned key only that peer is accepted, otherwise any Ed25519 key
+// is, and callers check it against what the peer claims to be.
+struct PeerVerifier {
+    pinned_key: Option<String>,
+}
+
+impl PeerVerifier {
+    fn check(&self, end_entity: &Certificate) -> Result<(), rustls::Error> {
+        let pub_key = get_cert_pub_key(&end_entity.0).ok_or(
+            rustls::Error::InvalidCertificate(CertificateError::BadEncoding),
+        )?;
+        match &self.pinned_key {
+            Some(pinned_key) if pinned_key != &pub_key => {
+                Err(rustls::Error::InvalidCertificate(
+                    CertificateError::ApplicationVerificationFailure,
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl ServerCertVerifier for PeerVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.check(end_entity)?;
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+impl ClientCertVerifier for PeerVerifier {
+    // Unpaired hosts have to reach /echo and /pair
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn client_auth_root_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _now: SystemTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        self.check(end_entity)?;
+        Ok(ClientCertVerified::assertion())
+    }
+}
+
+// The key the handshake was signed with, only Ed25519 keys are accepted
+pub fn get_cert_pub_key(cert_der: &[u8]) -> Option<String> {
+    let (rest, cert) = X509Certificate::from_der(cert_der).ok()?;
+    if !rest.is_empty() {
+        return None;
+    }
+    let spki = cert.public_key();
+    if spki.algorithm.algorithm != OID_SIG_ED25519
+        || spki.algorithm.parameters.is_some()
+        || spki.subject_public_key.unused_bits != 0
+        || spki.subject_public_key.data.len() != 32
+    {
+        return None;
+    }
+    Some(URL_SAFE_NO_PAD.encode(&spki.subject_public_key.data))
+}
+
+pub fn get_response_pub_key(response: &Response) -> Option<String> {
+    let tls_info = response.extensions().get::<TlsInfo>()?;
+    get_cert_pub_key(tls_info.peer_certificate()?)
+}
+
+// Self-signed certificate for an identity key of the node
+fn generate_cert(
+    pkcs8_bytes: Vec<u8>,
+) -> Result<(Certificate, PrivateKey), Error> {
+    let mut params = CertificateParams::new(vec!["resk".to_owned()]);
+    params.alg = &PKCS_ED25519;
+    params.key_pair = Some(KeyPair::from_der(&pkcs8_bytes)?);
+    let cert = rcgen::Certificate::from_params(params)?;
+    Ok((Certificate(cert.serialize_der()?), PrivateKey(pkcs8_bytes)))
+}
+
+// Serves a certificate for the current identity, which changes on rotation
+#[derive(Default)]
+struct NodeCertResolver {
+    current: RwLock<Option<(Vec<u8>, Arc<CertifiedKey>)>>,
+}
+
+impl NodeCertResolver {
+    fn certified_key(&self) -> Result<Arc<CertifiedKey>, Error> {
+        let pkcs8_bytes = load_sign_key_pkcs8()?;
+        if let Some((cached_key, certified_key)) =
+            self.current.read().unwrap().as_ref()
+        {
+            if cached_key == &pkcs8_bytes {
+                return Ok(certified_key.clone());
+            }
+        }
+
+        let (cert, key) = generate_cert(pkcs8_bytes.clone())?;
+        let signing_key = any_eddsa_type(&key)
+            .map_err(|err| Error::Generic(err.to_string().into()))?;
+        let certified_key =
+            Arc::new(CertifiedKey::new(vec![cert], signing_key));
+        *self.current.write().unwrap() =
+            Some((pkcs8_bytes, certified_key.clone()));
+        Ok(certified_key)
+    }
+}
+
+impl ResolvesServerCert for NodeCertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.certified_key()
+            .map_err(|err| log::error!("Failed to load certificate: {}", err))
+            .ok()
+    }
+}
+
+pub fn server_config() -> Result<ServerConfig, Error> {
+    let cert_resolver = NodeCertResolver::default();
+    cert_resolver.certified_key()?;
+    let config = ServerConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_client_cert_verifier(Arc::new(PeerVerifier { pinned_key: None }))
+        .with_cert_resolver(Arc::new(cert_resolver));
+    Ok(config)
+}
+
+pub fn client_config(
+    pinned_key: Option<&String>,
+) -> Result<ClientConfig, Error> {
+    identity_client_config(load_sign_key_pkcs8()?, pinned_key)
+}
+
+fn identity_client_config(
+    pkcs8_bytes: Vec<u8>,
+    pinned_key: Option<&String>,
+) -> Result<ClientConfig, Error> {
+    let (cert, key) = generate_cert(pkcs8_bytes)?;
+    let config = ClientConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_custom_certificate_verifier(Arc::new(PeerVerifier {
+            pinned_key: pinned_key.cloned(),
+        }))
+        .with_client_auth_cert(vec![cert], key)?;
+    Ok(config)
+}
+
+// Peers close idle connections after NODE_KEEP_ALIVE, drop them before
+const POOL_IDLE_TIMEOUT: u64 = 60;
+// Default for calls to peers, requests may set a shorter one
+const PEER_TIMEOUT: u64 = 3;
+
+// Client that only talks to the peer holding `pinned_key`
+pub fn build_client(
+    pinned_key: Option<&String>,
+    timeout: Duration,
+) -> Result<Client, Error> {
+    build_identity_client(
+        load_sign_key_pkcs8()?,
+        &get_node_id()?,
+        pinned_key,
+        timeout,
+    )
+}
+
+// Same, but presenting an identity that isn't installed (yet)
+pub fn build_client_as(
+    pkcs8_bytes: Vec<u8>,
+    verify_key: &String,
+    pinned_key: &String,
+) -> Result<Client, Error> {
+    build_identity_client(
+        pkcs8_bytes,
+        &get_fingerprint(verify_key)?,
+        Some(pinned_key),
+        Duration::from_secs(PEER_TIMEOUT),
+    )
+}
+
+fn build_identity_client(
+    pkcs8_bytes: Vec<u8>,
+    node_id: &str,
+    pinned_key: Option<&String>,
+    timeout: Duration,
+) -> Result<Client, Error> {
+    let mut headers = HeaderMap::new();
+    let peer_id = HeaderValue::from_str(node_id)
+        .map_err(|err| Error::Generic(err.into()))?;
+    headers.insert(PEER_ID_HEADER, peer_id);
+    let client = Client::builder()
+        .default_headers(headers)
+        .use_preconfigured_tls(identity_client_config(pkcs8_bytes, pinned_key)?)
+        .tls_info(true)
+        .timeout(timeout)
+        .pool_idle_timeout(Duration::from_secs(POOL_IDLE_TIMEOUT))
+        .build()?;
+    Ok(client)
+}
+
+// Keep-alive clients, one per peer key so every handshake stays pinned.
+// All of them are rebuilt when our own identity rotates.
+#[derive(Default)]
+pub struct PeerClient {
+    current: RwLock<(Vec<u8>, HashMap<String, Client>)>,
+}
+
+impl PeerClient {
+    // Client whose connections only come up with the peer holding `pub_key`
+    pub fn get(&self, pub_key: &str) -> Result<Client, Error> {
+        let pkcs8_bytes = load_sign_key_pkcs8()?;
+        {
+            let current = self.current.read().unwrap();
+            if current.0 == pkcs8_bytes {
+                if let Some(client) = current.1.get(pub_key) {
+                    return Ok(client.clone());
+                }
+            }
+        }
+
+        let client = build_client(
+            Some(&pub_key.to_owned()),
+            Duration::from_secs(PEER_TIMEOUT),
+        )?;
+        let mut current = self.current.write().unwrap();
+        if current.0 != pkcs8_bytes {
+            *current = (pkcs8_bytes, HashMap::new());
+        }
+        current.1.insert(pub_key.to_owned(), client.clone());
+        Ok(client)
+    }
+
+    // Hosts found by a scan aren't paired yet, callers check their key
+    pub fn unpinned(&self) -> Result<Client, Error> {
+        build_client(None, Duration::from_secs(PEER_TIMEOUT))
+    }
+
+    // Drops the connections to a key that was handed over or unpaired
+    pub fn forget(&self, pub_key: &str) {
+        self.current.write().unwrap().1.remove(pub_key);
+    }
+}
+
+pub fn on_connect(connection: &dyn Any, data: &mut Extensions) {
+    if let Some(tls_stream) = connection.downcast_ref::<TlsStream<TcpStream>>()
+    {
+        let (_, session) = tls_stream.get_ref();
+        let pub_key = session
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(|cert| get_cert_pub_key(&cert.0));
+        if let Some(pub_key) = pub_key {
+            data.insert(PeerIdentity(pub_key));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{Ed25519KeyPair, KeyPair as _};
+
+    #[test]
+    fn ed25519_cert_gives_its_key() {
+        let pkcs8 =
+            Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let (cert, _) = generate_cert(pkcs8.as_ref().to_vec()).unwrap();
+        assert_eq!(
+            get_cert_pub_key(&cert.0),
+            Some(URL_SAFE_NO_PAD.encode(key_pair.public_key()))
+        );
+    }
+
+    #[test]
+    fn other_key_types_are_rejected() {
+        // rcgen signs with a fresh ECDSA P-256 key by default
+        let params = CertificateParams::new(vec!["resk".to_owned()]);
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        assert_eq!(get_cert_pub_key(&cert.serialize_der().unwrap()), None);
+    }
+
+    #[test]
+    fn trailing_bytes_are_rejected() {
+        let pkcs8 =
+            Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let (cert, _) = generate_cert(pkcs8.as_ref().to_vec()).unwrap();
+        let mut cert_der = cert.0;
+        cert_der.push(0);
+        assert_eq!(get_cert_pub_key(&cert_der), None);
+    }
+}